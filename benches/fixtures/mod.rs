@@ -0,0 +1,79 @@
+//! Deterministic disk images used by the benchmarks
+//!
+//! Every fixture is generated from its parameters alone, so results saved with
+//! `--save-baseline` remain comparable across runs and machines.
+use io_at::{self, ReadAt, WriteAt};
+use io_block::{self, BlockSize};
+
+pub const BLOCK_SIZE: u64 = 512;
+
+/// A fixed size in-memory block device
+#[derive(Clone)]
+pub struct MemDisk {
+    pub data: Vec<u8>,
+}
+
+impl MemDisk {
+    pub fn new(block_count: u64) -> Self {
+        MemDisk { data: vec![0; (BLOCK_SIZE * block_count) as usize] }
+    }
+
+    /// Fill partition record `idx` of the MBR/EBR at `sector`
+    pub fn set_part(&mut self, sector: u64, idx: usize, part_type: u8, first: u32, size: u32) {
+        let s = (sector * BLOCK_SIZE) as usize;
+        let o = s + 446 + idx * 16;
+        self.data[o + 4] = part_type;
+        self.data[o + 8..o + 12].copy_from_slice(&first.to_le_bytes());
+        self.data[o + 12..o + 16].copy_from_slice(&size.to_le_bytes());
+        self.data[s + 510] = 0x55;
+        self.data[s + 511] = 0xAA;
+    }
+}
+
+impl ReadAt for MemDisk {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io_at::Result<usize> {
+        self.data.read_at(buf, offs)
+    }
+}
+
+impl WriteAt for MemDisk {
+    fn write_at(&mut self, buf: &[u8], offs: u64) -> io_at::Result<usize> {
+        let len = self.data.len() as u64;
+        if offs >= len {
+            return Ok(0);
+        }
+        let n = ::std::cmp::min(buf.len() as u64, len - offs) as usize;
+        self.data.write_at(&buf[..n], offs)
+    }
+}
+
+impl BlockSize for MemDisk {
+    fn block_size_logical(&self) -> io_block::Result<u64> {
+        Ok(BLOCK_SIZE)
+    }
+
+    fn block_count(&self) -> io_block::Result<u64> {
+        Ok(self.data.len() as u64 / BLOCK_SIZE)
+    }
+}
+
+/// Start of the extended partition in `ebr_chain_disk()`
+const EXT_FIRST: u64 = 2048;
+
+/// A MBR disk with 1 primary partition and an extended partition holding `logicals` 1 block
+/// logical partitions, each directly after its EBR. 2048 blocks are left free at the end so the
+/// disk can be converted to GPT.
+pub fn ebr_chain_disk(logicals: u32) -> MemDisk {
+    let ext_size = logicals as u64 * 2;
+    let mut d = MemDisk::new(EXT_FIRST + ext_size + 2048);
+    d.set_part(0, 0, 0x83, 1024, 1024);
+    d.set_part(0, 1, 0x05, EXT_FIRST as u32, ext_size as u32);
+    for i in 0..logicals {
+        let ebr = EXT_FIRST + i as u64 * 2;
+        d.set_part(ebr, 0, 0x83, 1, 1);
+        if i + 1 < logicals {
+            d.set_part(ebr, 1, 0x05, (i + 1) * 2, 2);
+        }
+    }
+    d
+}
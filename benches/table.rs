@@ -0,0 +1,84 @@
+//! Parse, validate, plan and serialize benchmarks
+//!
+//! Run with `cargo bench --features bench`. To track regressions, record a baseline and compare
+//! later runs against it:
+//!
+//! ```text
+//! cargo bench --features bench -- --save-baseline main
+//! cargo bench --features bench -- --baseline main
+//! ```
+//!
+//! Reference numbers from a full run are kept in `benches/BASELINE.md`.
+//!
+//! There is no layout solver yet: "validate" covers `MbrBuilder::compile()`, which checks specs
+//! without placing partitions, and "plan" covers planning an MBR to GPT conversion.
+#[macro_use]
+extern crate criterion;
+extern crate drive_part;
+extern crate io_at;
+extern crate io_block;
+
+mod fixtures;
+
+use criterion::{black_box, BatchSize, Criterion};
+use drive_part::convert::{ConvertMode, ConvertPlan};
+use drive_part::gpt::{self, GptEntry, GptLayout};
+use drive_part::mbr::MbrReader;
+use drive_part::mbr::writer::{MbrBuilder, MbrPartSpec};
+use fixtures::ebr_chain_disk;
+
+fn parse(c: &mut Criterion) {
+    let r = MbrReader::from_blockdev(ebr_chain_disk(1000));
+    c.bench_function("parse/ebr_chain_1000", |b| {
+        b.iter(|| black_box(r.logical_partitions().unwrap()))
+    });
+    c.bench_function("parse/stats", |b| {
+        b.iter(|| black_box(r.stats().unwrap()))
+    });
+}
+
+fn validate(c: &mut Criterion) {
+    let builder = (0..100).fold(MbrBuilder::new().set_max_logical(100),
+                                |b, _| b.partition_add(MbrPartSpec::new(vec![])));
+    c.bench_function("validate/mbr_compile_100", |b| {
+        b.iter(|| black_box(builder.clone()).compile().unwrap())
+    });
+}
+
+fn plan(c: &mut Criterion) {
+    let d = ebr_chain_disk(99);
+    c.bench_function("plan/gpt_convert_100", |b| {
+        b.iter(|| black_box(ConvertPlan::new(&d, ConvertMode::RequireZeroed).unwrap()))
+    });
+}
+
+fn serialize(c: &mut Criterion) {
+    let l = GptLayout::new(fixtures::BLOCK_SIZE, 1 << 21);
+    let entries: Vec<GptEntry> = (0..l.entries as u64).map(|i| GptEntry {
+        type_guid: gpt::TYPE_LINUX_FILESYSTEM,
+        unique_guid: gpt::guid(i as u32, 0, 0, [0;8]),
+        first_lba: 2048 + i * 2048,
+        last_lba: 2048 + i * 2048 + 2047,
+        attributes: 0,
+        name: format!("part{}", i),
+    }).collect();
+    let disk_guid = gpt::guid(0xD15C, 0, 0, [0;8]);
+
+    c.bench_function("serialize/gpt_entry_array_128", |b| {
+        b.iter(|| black_box(l.entry_array(&entries)))
+    });
+    let array = l.entry_array(&entries);
+    c.bench_function("serialize/gpt_headers", |b| {
+        b.iter(|| (black_box(l.header(true, &disk_guid, &array)),
+                   black_box(l.header(false, &disk_guid, &array))))
+    });
+
+    let d = ebr_chain_disk(99);
+    let plan = ConvertPlan::new(&d, ConvertMode::RequireZeroed).unwrap();
+    c.bench_function("serialize/gpt_convert_commit_100", |b| {
+        b.iter_batched_ref(|| d.clone(), |d| plan.commit(d).unwrap(), BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, parse, validate, plan, serialize);
+criterion_main!(benches);
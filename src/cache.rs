@@ -0,0 +1,203 @@
+//! Caching of parsed partition tables for long running processes
+//!
+//! A `DiskCache` keeps the MBR and logical partitions of each device it has been asked about, so
+//! repeated layout queries don't need to go back to the device. Each entry is tagged with a caller
+//! supplied "stamp" that changes whenever the device may have changed: a file modification time
+//! (`std::fs::Metadata::modified()`), a counter bumped by a udev/inotify watcher, a media change
+//! counter from an ioctl, etc. A read with a stamp that differs from the cached one reloads the
+//! entry. Entries may also be dropped explicitly with `invalidate()` when an event arrives.
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use io_at;
+use io_at::{ReadAt};
+use io_block::{BlockSize};
+use mbr::MbrReader;
+use mbr::ebr::LogicalPartition;
+use mbr::header::MbrHeader;
+use mbr::stats::{self, MbrStats};
+
+/// A cached copy of a device's partition table and geometry
+#[derive(Clone)]
+pub struct CachedDisk<S> {
+    stamp: S,
+    sector0: [u8;512],
+    logical: Vec<LogicalPartition>,
+    block_size: u64,
+    block_count: u64,
+}
+
+impl<S> CachedDisk<S> {
+    /// The stamp supplied when this entry was read
+    pub fn stamp(&self) -> &S {
+        &self.stamp
+    }
+
+    pub fn header(&self) -> MbrHeader<'_> {
+        MbrHeader::from_bytes(&self.sector0)
+    }
+
+    /// See `MbrReader::logical_partitions()`
+    pub fn logical_partitions(&self) -> &[LogicalPartition] {
+        &self.logical
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    /// See `MbrReader::stats()`
+    pub fn stats(&self) -> MbrStats {
+        self.header().stats_with_logicals(self.block_size, self.block_count, stats::DEFAULT_ALIGN,
+                                          &self.logical)
+    }
+}
+
+fn load<S, T: ReadAt + BlockSize>(stamp: S, reader: &MbrReader<T>) -> io_at::Result<CachedDisk<S>> {
+    let store = reader.store();
+    Ok(CachedDisk {
+        stamp,
+        sector0: reader.read_header()?,
+        logical: reader.logical_partitions()?,
+        block_size: store.block_size_logical()?,
+        block_count: store.block_count()?,
+    })
+}
+
+/// Parsed tables for a set of devices, keyed by `K` (typically a device path) and validated by
+/// stamps of type `S`.
+pub struct DiskCache<K, S> {
+    entries: HashMap<K, CachedDisk<S>>,
+}
+
+impl<K: Hash + Eq, S: PartialEq> DiskCache<K, S> {
+    pub fn new() -> Self {
+        DiskCache { entries: HashMap::new() }
+    }
+
+    /// Return the entry for `key`, (re)reading it from `reader` if there is no entry yet or if
+    /// the cached entry's stamp is not equal to `stamp`.
+    ///
+    /// On error, any existing entry for `key` is dropped.
+    pub fn get<T: ReadAt + BlockSize>(&mut self, key: K, stamp: S, reader: &MbrReader<T>)
+        -> io_at::Result<&CachedDisk<S>>
+    {
+        match self.entries.entry(key) {
+            Entry::Occupied(o) => {
+                if o.get().stamp == stamp {
+                    return Ok(o.into_mut());
+                }
+                match load(stamp, reader) {
+                    Ok(e) => {
+                        let v = o.into_mut();
+                        *v = e;
+                        Ok(v)
+                    },
+                    Err(err) => {
+                        o.remove();
+                        Err(err)
+                    }
+                }
+            },
+            Entry::Vacant(v) => Ok(v.insert(load(stamp, reader)?)),
+        }
+    }
+
+    /// Return the entry for `key` without checking whether it is still current
+    pub fn peek(&self, key: &K) -> Option<&CachedDisk<S>> {
+        self.entries.get(key)
+    }
+
+    /// Drop the entry for `key`, returning true if there was one
+    pub fn invalidate(&mut self, key: &K) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Drop all entries
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: Hash + Eq, S: PartialEq> Default for DiskCache<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_disk::MemDisk;
+
+    #[test]
+    fn reload_on_stamp_change() {
+        let mut d = MemDisk::new(512, 4096);
+        d.set_mbr_part(0, 0x83, 2048, 1024);
+        let mut c = DiskCache::new();
+
+        assert_eq!(c.get("sda", 1, &MbrReader::from_blockdev(&d)).unwrap()
+                   .header().primary_partitions()[0].part_type(), 0x83);
+
+        /* same stamp: the (now stale) cached copy is served */
+        d.set_mbr_part(0, 0x07, 2048, 1024);
+        assert_eq!(c.get("sda", 1, &MbrReader::from_blockdev(&d)).unwrap()
+                   .header().primary_partitions()[0].part_type(), 0x83);
+
+        /* new stamp: re-read */
+        let e = c.get("sda", 2, &MbrReader::from_blockdev(&d)).unwrap();
+        assert_eq!(*e.stamp(), 2);
+        assert_eq!(e.header().primary_partitions()[0].part_type(), 0x07);
+        assert_eq!(e.block_count(), 4096);
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn logicals() {
+        let mut d = MemDisk::new(512, 8192);
+        d.set_mbr_part(0, 0x83, 64, 1024);
+        d.set_mbr_part(1, 0x05, 2048, 6144);
+        d.set_part(2048, 0, 0x83, 2048, 1024);
+        d.set_part(2048, 1, 0x05, 2048, 4096);
+        d.set_part(4096, 0, 0x82, 64, 512);
+        let r = MbrReader::from_blockdev(&d);
+
+        let mut c = DiskCache::new();
+        let e = c.get("sda", (), &r).unwrap();
+        assert_eq!(e.logical_partitions(), &r.logical_partitions().unwrap()[..]);
+        assert_eq!(e.stats(), r.stats().unwrap());
+    }
+
+    #[test]
+    fn invalidate() {
+        let d = MemDisk::new(512, 64);
+        let mut c = DiskCache::new();
+        c.get("sda", (), &MbrReader::from_blockdev(&d)).unwrap();
+        assert!(c.peek(&"sda").is_some());
+        assert!(c.invalidate(&"sda"));
+        assert!(!c.invalidate(&"sda"));
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn error_drops_entry() {
+        let d = MemDisk::new(512, 64);
+        let mut c = DiskCache::new();
+        c.get("sda", 1, &MbrReader::from_blockdev(&d)).unwrap();
+        let short = MemDisk::new(256, 1);
+        assert!(c.get("sda", 2, &MbrReader::from_blockdev(&short)).is_err());
+        assert!(c.peek(&"sda").is_none());
+    }
+}
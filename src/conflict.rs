@@ -0,0 +1,163 @@
+//! Detection of disks carrying both a MBR and a GPT that disagree
+//!
+//! A GPT disk is expected to have a protective MBR (a single 0xEE partition record). Tools that
+//! are unaware of GPT (or that only clear the first sector) can leave a disk with real MBR
+//! partitions and a stale GPT, or with a GPT and a MBR that was rewritten from scratch. Which of
+//! the two is used then depends on who is looking.
+use io_at;
+use io_at::{ReadAt};
+use io_block::{BlockSize};
+use gpt;
+use gpt::GptHeader;
+use mbr::header::MbrHeader;
+use remedy::Remedy;
+
+/// Which partition table will be used when both are present
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Authority {
+    /// The MBR is used. UEFI firmware (UEFI 2.6, 5.2.3) and Linux (unless booted with `gpt`)
+    /// only consider a GPT valid when it is accompanied by a protective MBR.
+    Mbr,
+    /// A hybrid MBR: UEFI firmware and GPT aware OSes use the GPT, while BIOS firmware and
+    /// GPT unaware OSes use the MBR partitions.
+    Split,
+}
+
+/// A MBR with partitions other than a protective one coexisting with a GPT
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Conflict {
+    /// The MBR also contains a 0xEE record (a hybrid MBR)
+    pub hybrid: bool,
+    /// A valid GPT header was found at LBA 1
+    pub primary_gpt: bool,
+    /// A valid GPT header was found at the last LBA
+    pub backup_gpt: bool,
+    pub authority: Authority,
+    /// Possible fixes, the most likely to match the intent of the disk's owner first
+    pub remedies: Vec<Remedy>,
+}
+
+/// Examine the MBR and GPT headers of `dev`
+///
+/// Returns `None` when there is no conflict: there is no GPT, or the MBR is protective (or
+/// absent).
+pub fn check<T: ReadAt + BlockSize>(dev: &T) -> io_at::Result<Option<Conflict>> {
+    let mut m = [0u8;512];
+    ::read_exact_at(dev, &mut m, 0)?;
+    let (primary, backup) = gpt::read_headers(dev)?;
+    Ok(check_blocks(&m, &primary, &backup))
+}
+
+/// Like `check()`, but on already read MBR, primary GPT header and backup GPT header blocks
+pub fn check_blocks(mbr: &[u8;512], primary: &[u8;512], backup: &[u8;512]) -> Option<Conflict> {
+    let primary_gpt = GptHeader::from_bytes(primary).is_valid();
+    let backup_gpt = GptHeader::from_bytes(backup).is_valid();
+    let m = MbrHeader::from_bytes(mbr);
+
+    if !(primary_gpt || backup_gpt) || !m.bootsig_is_valid() || m.is_protective() {
+        return None;
+    }
+
+    let others = m.primary_partitions().iter()
+        .filter(|p| p.is_used() && p.part_type() != gpt::PROTECTIVE_MBR_TYPE)
+        .count();
+    if others == 0 {
+        return None;
+    }
+
+    let hybrid = m.has_protective_entry();
+    let (authority, remedies) = if hybrid {
+        /* someone went to the trouble of building a hybrid, the GPT is most likely intended */
+        (Authority::Split, vec![Remedy::RebuildProtectiveMbr])
+    } else {
+        (Authority::Mbr, vec![Remedy::ZapStaleGpt, Remedy::RebuildProtectiveMbr])
+    };
+
+    Some(Conflict {
+        hybrid,
+        primary_gpt,
+        backup_gpt,
+        authority,
+        remedies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpt::crc32;
+    use mem_disk::MemDisk;
+
+    fn put_gpt_header(d: &mut MemDisk, my_lba: u64, alt_lba: u64) {
+        let mut h = [0u8;92];
+        h[..8].copy_from_slice(&gpt::SIGNATURE);
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        h[32..40].copy_from_slice(&alt_lba.to_le_bytes());
+        let c = crc32(&h);
+        h[16..20].copy_from_slice(&c.to_le_bytes());
+        let o = my_lba as usize * 512;
+        d.data[o..o + 92].copy_from_slice(&h);
+    }
+
+    fn gpt_disk() -> MemDisk {
+        let mut d = MemDisk::new(512, 4096);
+        put_gpt_header(&mut d, 1, 4095);
+        put_gpt_header(&mut d, 4095, 1);
+        d
+    }
+
+    #[test]
+    fn no_conflict() {
+        let mut d = MemDisk::new(512, 4096);
+        d.set_mbr_part(0, 0x83, 2048, 1024);
+        assert_eq!(check(&d).unwrap(), None);
+
+        let mut d = gpt_disk();
+        d.set_mbr_part(0, 0xEE, 1, 4095);
+        assert_eq!(check(&d).unwrap(), None);
+    }
+
+    #[test]
+    fn stale_gpt() {
+        let mut d = gpt_disk();
+        d.set_mbr_part(0, 0x83, 2048, 1024);
+        let c = check(&d).unwrap().unwrap();
+        assert_eq!(c.authority, Authority::Mbr);
+        assert!(!c.hybrid && c.primary_gpt && c.backup_gpt);
+        assert_eq!(c.remedies[0], Remedy::ZapStaleGpt);
+
+        c.remedies[0].apply(&mut d).unwrap();
+        assert_eq!(check(&d).unwrap(), None);
+        assert!(!GptHeader::from_bytes(index_fixed!(&d.data; 512, .. 1024)).is_valid());
+        assert_eq!(MbrHeader::from_bytes(index_fixed!(&d.data; 0, .. 512))
+                   .primary_partitions()[0].part_type(), 0x83);
+    }
+
+    #[test]
+    fn hybrid() {
+        let mut d = gpt_disk();
+        d.set_mbr_part(0, 0xEE, 1, 2047);
+        d.set_mbr_part(1, 0x0c, 2048, 1024);
+        let c = check(&d).unwrap().unwrap();
+        assert_eq!(c.authority, Authority::Split);
+        assert!(c.hybrid);
+
+        c.remedies[0].apply(&mut d).unwrap();
+        assert_eq!(check(&d).unwrap(), None);
+        assert!(MbrHeader::from_bytes(index_fixed!(&d.data; 0, .. 512)).is_protective());
+    }
+
+    #[test]
+    fn refuse_destructive() {
+        let mut d = gpt_disk();
+        /* the "header" at LBA 1 is inside a partition */
+        d.set_mbr_part(0, 0x83, 1, 1024);
+        assert!(Remedy::ZapStaleGpt.apply(&mut d).is_err());
+
+        let mut d = MemDisk::new(512, 4096);
+        d.set_mbr_part(0, 0x83, 2048, 1024);
+        assert!(Remedy::RebuildProtectiveMbr.apply(&mut d).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! In place conversion of a populated MBR disk to GPT
+//!
+//! This is the library equivalent of gdisk's conversion: the partitions described by the MBR
+//! (and its EBR chain) are described again by a GPT, without moving or touching their contents.
+//! Only blocks outside of all partitions are written.
+//!
+//! Conversion is split in 2 steps, mirroring `MbrBuilder::compile()`/`MbrWriter::commit()`:
+//! `ConvertPlan::new()` reads the disk and checks that the blocks the GPT needs (LBA 1 through the
+//! end of the primary partition entry array, and the backup array and header at the end of the
+//! disk) are available, and `ConvertPlan::commit()` writes the new structures.
+//!
+//! Data found in those blocks that isn't part of any partition (for example, a boot loader
+//! embedded after the MBR) is either refused (`ConvertMode::RequireZeroed`), or copied into free
+//! space before the GPT is written over it (`ConvertMode::Relocate`).
+use std::io;
+use io_at::{ReadAt, WriteAt};
+use io_block::{BlockSize};
+use gpt;
+use gpt::{GptEntry, GptLayout};
+use limits::LimitExceeded;
+use mbr::ebr;
+use mbr::header::{MbrHeader, PartitionStatus};
+
+/// How strict to be about the blocks the GPT structures will occupy
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum ConvertMode {
+    /// The blocks must be outside every partition and contain only zeros.
+    RequireZeroed,
+    /// The blocks must be outside every partition. Any data in them is copied to zeroed,
+    /// unpartitioned blocks elsewhere on the disk first (see `ConvertPlan::relocations`),
+    /// together with any unpartitioned data directly following or preceding it.
+    Relocate,
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    Io(io::Error),
+    /// Sector 0 does not have a valid boot signature
+    NoMbr,
+    /// The MBR is already protective or hybrid
+    AlreadyGpt,
+    /// The device is too small to hold the GPT structures
+    DeviceTooSmall,
+    /// More than one primary partition is an extended partition. Only the first EBR chain would
+    /// be understood, so the logical partitions of the others would be lost.
+    MultipleExtended,
+    /// The partition starting at `.0` overlaps the primary GPT structures (which end before
+    /// `.1`)
+    PrimaryAreaInUse(u64, u64),
+    /// The partition ending at `.0` (inclusive) overlaps the backup GPT structures (which start
+    /// at `.1`)
+    BackupAreaInUse(u64, u64),
+    /// `ConvertMode::RequireZeroed` was requested, but block `.0` contains data
+    AreaNotZeroed(u64),
+    /// `ConvertMode::Relocate` was requested, but there is no run of `.0` zeroed, unpartitioned
+    /// blocks to move the data to
+    NoRoomToRelocate(u64),
+    /// More partitions than GPT entries
+    TooManyPartitions(LimitExceeded),
+    /// The device's geometry changed between planning and commit
+    GeometryChanged,
+}
+
+impl From<io::Error> for ConvertError {
+    fn from(e: io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
+
+/// Blocks copied out of the way of the GPT structures by `ConvertPlan::commit()`
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct Relocation {
+    pub from_lba: u64,
+    pub to_lba: u64,
+    pub blocks: u64,
+}
+
+fn block_is_zero<T: ReadAt>(dev: &T, b: &mut [u8], lba: u64) -> io::Result<bool> {
+    ::read_exact_at(dev, b, lba * b.len() as u64)?;
+    Ok(b.iter().all(|&x| x == 0))
+}
+
+/// Find `len` zeroed blocks that are not in any of the `used` (sorted, `[first, end)`) extents
+fn find_free<T: ReadAt>(dev: &T, b: &mut [u8], used: &[(u64, u64)], block_count: u64, len: u64)
+    -> io::Result<Option<u64>>
+{
+    let mut gap_start = 0;
+    for &(first, end) in used.iter().chain([(block_count, block_count)].iter()) {
+        /* scan the gap before this extent, restarting after any non-zero block */
+        let mut cand = gap_start;
+        let mut lba = gap_start;
+        while lba < first && cand + len <= first {
+            if !block_is_zero(dev, b, lba)? {
+                cand = lba + 1;
+            } else if lba + 1 - cand == len {
+                return Ok(Some(cand));
+            }
+            lba += 1;
+        }
+        gap_start = std::cmp::max(gap_start, end);
+    }
+    Ok(None)
+}
+
+/// A checked conversion, ready to be committed
+#[derive(Clone,Debug)]
+pub struct ConvertPlan {
+    pub layout: GptLayout,
+    pub disk_guid: [u8;16],
+    /// Primary partitions in MBR slot order followed by logical partitions in EBR chain order.
+    /// Extended partitions themselves are not carried over.
+    pub entries: Vec<GptEntry>,
+    /// Data moved out of the GPT areas with `ConvertMode::Relocate`.
+    ///
+    /// The data is copied as is. Anything that refers to it by LBA (such as boot code in the MBR
+    /// pointing at an embedded boot loader) has to be updated by the caller. The new location
+    /// is not a partition, so later partitioning may overwrite it.
+    pub relocations: Vec<Relocation>,
+    mbr: [u8;512],
+}
+
+impl ConvertPlan {
+    /// Read the MBR (and EBR chain) from `dev` and check that a GPT can be added around the
+    /// existing partitions.
+    pub fn new<T: ReadAt + BlockSize>(dev: &T, mode: ConvertMode) -> Result<Self, ConvertError> {
+        let mut mbr = [0u8;512];
+        ::read_exact_at(dev, &mut mbr, 0)?;
+        let h = MbrHeader::from_bytes(&mbr);
+        if !h.bootsig_is_valid() {
+            return Err(ConvertError::NoMbr);
+        }
+        if h.has_protective_entry() {
+            return Err(ConvertError::AlreadyGpt);
+        }
+
+        let layout = GptLayout::new(dev.block_size_logical()?, dev.block_count()?);
+        if !layout.fits() {
+            return Err(ConvertError::DeviceTooSmall);
+        }
+
+        /* (type, bootable, first, size) */
+        let mut parts = vec![];
+        let mut logical = vec![];
+        let mut ext_seen = false;
+        /* blocks that relocated data must not be moved to: [first, end) */
+        let mut used = vec![];
+        for p in h.primary_partitions().iter().filter(|p| p.is_used()) {
+            let (first, size) = (p.lba_first() as u64, p.lba_size() as u64);
+            if !ebr::is_extended_type(p.part_type()) {
+                parts.push((p.part_type(), matches!(p.status(), PartitionStatus::Active),
+                            first, size));
+            } else if ext_seen {
+                return Err(ConvertError::MultipleExtended);
+            } else {
+                ext_seen = true;
+                /* the EBRs live in the gaps between logical partitions */
+                used.push((first, first + size));
+                logical = ebr::read_chain(dev, first, size)?;
+            }
+        }
+        for l in logical.into_iter() {
+            parts.push((l.part_type, l.bootable, l.lba_first, l.lba_size));
+        }
+
+        layout.check_count(parts.len()).map_err(ConvertError::TooManyPartitions)?;
+
+        let first_usable = layout.first_usable_lba();
+        let last_usable = layout.last_usable_lba();
+        for &(_, _, first, size) in parts.iter() {
+            if first < first_usable {
+                return Err(ConvertError::PrimaryAreaInUse(first, first_usable));
+            }
+            if first + size - 1 > last_usable {
+                return Err(ConvertError::BackupAreaInUse(first + size - 1, last_usable + 1));
+            }
+        }
+
+        let mut b = vec![0u8; layout.block_size as usize];
+        let areas = [(1, first_usable), (last_usable + 1, layout.block_count)];
+        used.push((0, first_usable));
+        used.push((last_usable + 1, layout.block_count));
+        used.extend(parts.iter().map(|&(_, _, first, size)| (first, first + size)));
+        used.sort();
+
+        let mut relocations = vec![];
+        for &(start, end) in areas.iter() {
+            /* move everything from the first to the last non-zero block together, so data that
+             * spans several blocks stays contiguous */
+            let mut span: Option<(u64, u64)> = None;
+            for lba in start..end {
+                if !block_is_zero(dev, &mut b, lba)? {
+                    span = Some((span.map_or(lba, |s| s.0), lba + 1));
+                }
+            }
+            let (mut from, mut to) = match span {
+                Some(s) => s,
+                None => continue,
+            };
+            /* data touching the edge of the area may continue outside of it (such as a boot
+             * loader embedded from LBA 1 up to the first partition): take the whole run */
+            let in_use = |lba: u64| used.iter().any(|&(f, e)| f <= lba && lba < e);
+            if to == end {
+                while to < layout.block_count && !in_use(to) && !block_is_zero(dev, &mut b, to)? {
+                    to += 1;
+                }
+            }
+            if from == start {
+                while from > 0 && !in_use(from - 1) && !block_is_zero(dev, &mut b, from - 1)? {
+                    from -= 1;
+                }
+            }
+
+            match mode {
+                ConvertMode::RequireZeroed => return Err(ConvertError::AreaNotZeroed(from)),
+                ConvertMode::Relocate => {
+                    let blocks = to - from;
+                    let dest = find_free(dev, &mut b, &used, layout.block_count, blocks)?
+                        .ok_or(ConvertError::NoRoomToRelocate(blocks))?;
+                    used.push((dest, dest + blocks));
+                    used.sort();
+                    relocations.push(Relocation { from_lba: from, to_lba: dest, blocks });
+                },
+            }
+        }
+
+        let entries = parts.into_iter().map(|(t, bootable, first, size)| GptEntry {
+            type_guid: gpt::type_for_mbr(t),
+            unique_guid: gpt::random_guid(),
+            first_lba: first,
+            last_lba: first + size - 1,
+            attributes: if bootable { gpt::ATTR_LEGACY_BIOS_BOOTABLE } else { 0 },
+            name: String::new(),
+        }).collect();
+
+        Ok(ConvertPlan {
+            layout,
+            disk_guid: gpt::random_guid(),
+            entries,
+            relocations,
+            mbr,
+        })
+    }
+
+    /// Copy any relocated data, then write the GPT structures and replace the MBR with a
+    /// protective MBR (keeping its bootcode and disk signature).
+    ///
+    /// Relocations are copies, and happen before anything else is written. The backup structures
+    /// are written first and the protective MBR last, so that an interrupted commit leaves the
+    /// original MBR in charge (a GPT without a protective MBR is ignored).
+    ///
+    /// As with `MbrWriter::commit()`, it is recommended that you ensure no changes are made to the
+    /// device between planning and commit.
+    pub fn commit<T: ReadAt + WriteAt + BlockSize>(&self, dev: &mut T)
+        -> Result<(), ConvertError>
+    {
+        let l = &self.layout;
+        if dev.block_size_logical()? != l.block_size || dev.block_count()? != l.block_count {
+            return Err(ConvertError::GeometryChanged);
+        }
+        let bs = l.block_size;
+
+        let mut b = vec![0u8; bs as usize];
+        for r in self.relocations.iter() {
+            for i in 0..r.blocks {
+                ::read_exact_at(&*dev, &mut b, (r.from_lba + i) * bs)?;
+                dev.write_all_at(&b, (r.to_lba + i) * bs)?;
+            }
+        }
+
+        let array = l.entry_array(&self.entries);
+
+        dev.write_all_at(&array, l.backup_array_lba() * bs)?;
+        dev.write_all_at(&l.header(false, &self.disk_guid, &array), l.last_lba() * bs)?;
+        dev.write_all_at(&array, l.primary_array_lba() * bs)?;
+        dev.write_all_at(&l.header(true, &self.disk_guid, &array), bs)?;
+        dev.write_all_at(&gpt::protective_mbr(&self.mbr, l.block_count), 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conflict;
+    use gpt::GptHeader;
+    use mem_disk::MemDisk;
+
+    /* 8 MiB: a primary, and an extended holding 2 logicals. Partition contents are filled. */
+    fn mbr_disk() -> MemDisk {
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x0c, 2048, 4096);
+        d.data[446] = 0x80;
+        d.set_mbr_part(1, 0x05, 6144, 8192);
+        d.set_part(6144, 0, 0x83, 2048, 2048);
+        d.set_part(6144, 1, 0x05, 4096, 4096);
+        d.set_part(10240, 0, 0x82, 2048, 2048);
+        for &(first, size) in [(2048, 4096), (8192, 2048), (12288, 2048)].iter() {
+            for b in d.data[first * 512..(first + size) * 512].iter_mut() {
+                *b = 0xA5;
+            }
+        }
+        d.data[..446].copy_from_slice(&[0x33; 446]);
+        d
+    }
+
+    #[test]
+    fn convert() {
+        let mut d = mbr_disk();
+        let before = d.data.clone();
+        let p = ConvertPlan::new(&d, ConvertMode::RequireZeroed).unwrap();
+        assert_eq!(p.entries.len(), 3);
+        p.commit(&mut d).unwrap();
+
+        /* partition contents are untouched */
+        for &(first, size) in [(2048, 4096), (8192, 2048), (12288, 2048)].iter() {
+            let r = first * 512..(first + size) * 512;
+            assert!(d.data[r.clone()] == before[r]);
+        }
+
+        let m = MbrHeader::from_bytes(index_fixed!(&d.data; 0, .. 512));
+        assert!(m.is_protective());
+        assert_eq!(&d.data[..446], &[0x33; 446][..]);
+        assert_eq!(conflict::check(&d).unwrap(), None);
+
+        let (primary, backup) = gpt::read_headers(&d).unwrap();
+        let h = GptHeader::from_bytes(&primary);
+        assert!(h.is_valid() && GptHeader::from_bytes(&backup).is_valid());
+        assert_eq!(h.num_partition_entries(), 128);
+        let a = &d.data[1024..1024 + 128 * 128];
+        assert_eq!(h.partition_entry_array_crc32(), gpt::crc32(a));
+
+        let r64 = |x: &[u8]| u64::from_le_bytes(*index_fixed!(&x; 0, .. 8));
+        let e: Vec<(u64, u64, [u8;16], u64)> = a.chunks(128).take(4).map(|e| {
+            (r64(&e[32..]), r64(&e[40..]), *index_fixed!(&e; 0, .. 16), r64(&e[48..]))
+        }).collect();
+        assert_eq!(e[0], (2048, 6143, gpt::TYPE_BASIC_DATA, gpt::ATTR_LEGACY_BIOS_BOOTABLE));
+        assert_eq!(e[1], (8192, 10239, gpt::TYPE_LINUX_FILESYSTEM, 0));
+        assert_eq!(e[2], (12288, 14335, gpt::TYPE_LINUX_SWAP, 0));
+        assert_eq!(e[3], (0, 0, [0;16], 0));
+    }
+
+    #[test]
+    fn relocate() {
+        let mut d = mbr_disk();
+        /* an embedded boot loader after the MBR, and something in the backup GPT area */
+        for (i, b) in d.data[512 * 3..512 * 6].iter_mut().enumerate() {
+            *b = i as u8 | 1;
+        }
+        d.data[512 * 16380] = 7;
+        /* not zeroed, so the 3 block run can't start at 34 */
+        d.data[512 * 35] = 9;
+        let before = d.data.clone();
+
+        let p = ConvertPlan::new(&d, ConvertMode::Relocate).unwrap();
+        assert_eq!(p.relocations, vec![
+            Relocation { from_lba: 3, to_lba: 36, blocks: 3 },
+            Relocation { from_lba: 16380, to_lba: 34, blocks: 1 },
+        ]);
+        p.commit(&mut d).unwrap();
+
+        assert!(d.data[512 * 36..512 * 39] == before[512 * 3..512 * 6]);
+        assert!(d.data[512 * 34..512 * 35] == before[512 * 16380..512 * 16381]);
+        assert_eq!(d.data[512 * 35], 9);
+        assert!(GptHeader::from_bytes(&gpt::read_headers(&d).unwrap().0).is_valid());
+    }
+
+    #[test]
+    fn relocate_past_area() {
+        let mut d = mbr_disk();
+        /* a boot loader from LBA 1 up to 62, crossing the end of the primary GPT area at 34 */
+        for b in d.data[512..512 * 63].iter_mut() {
+            *b = 0x5A;
+        }
+        let before = d.data.clone();
+
+        let p = ConvertPlan::new(&d, ConvertMode::Relocate).unwrap();
+        assert_eq!(p.relocations, vec![Relocation { from_lba: 1, to_lba: 63, blocks: 62 }]);
+        p.commit(&mut d).unwrap();
+        assert!(d.data[512 * 63..512 * 125] == before[512..512 * 63]);
+    }
+
+    #[test]
+    fn blocked() {
+        let mut d = mbr_disk();
+        d.data[512 * 3] = 1;
+        match ConvertPlan::new(&d, ConvertMode::RequireZeroed) {
+            Err(ConvertError::AreaNotZeroed(3)) => {},
+            r => panic!("{:?}", r),
+        }
+        assert!(ConvertPlan::new(&d, ConvertMode::Relocate).is_ok());
+
+        /* no zeroed, unpartitioned space anywhere */
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x83, 34, 16351 - 34);
+        d.data[512 * 3] = 1;
+        match ConvertPlan::new(&d, ConvertMode::Relocate) {
+            Err(ConvertError::NoRoomToRelocate(1)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x83, 20, 1000);
+        match ConvertPlan::new(&d, ConvertMode::Relocate) {
+            Err(ConvertError::PrimaryAreaInUse(20, 34)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x83, 2048, 16384 - 2048);
+        match ConvertPlan::new(&d, ConvertMode::Relocate) {
+            Err(ConvertError::BackupAreaInUse(16383, 16351)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let mut d = mbr_disk();
+        d.set_mbr_part(2, 0x0F, 14336, 1024);
+        d.set_part(14336, 0, 0x83, 64, 512);
+        match ConvertPlan::new(&d, ConvertMode::Relocate) {
+            Err(ConvertError::MultipleExtended) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0xEE, 1, 16383);
+        match ConvertPlan::new(&d, ConvertMode::Relocate) {
+            Err(ConvertError::AlreadyGpt) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+}
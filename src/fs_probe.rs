@@ -0,0 +1,274 @@
+//! Filesystem size probing, to catch partition changes that would cut off a filesystem
+//!
+//! Before shrinking or deleting a partition, `resize_impact()` or `delete_impact()` can be used to
+//! look for a filesystem superblock at the start of the partition and compare the size it
+//! records with the new partition size. This is opt-in: nothing else in this crate reads
+//! partition contents.
+//!
+//! A superblock whose recorded size overflows a u64 is treated as not recognized.
+//!
+//! Recognized: ext2/3/4, XFS, btrfs, NTFS and FAT12/16/32.
+use std::io::ErrorKind;
+use io_at;
+use io_at::{ReadAt};
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum FsKind {
+    Ext,
+    Xfs,
+    Btrfs,
+    Ntfs,
+    Fat,
+}
+
+/// A filesystem found by `probe()`
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct FsInfo {
+    pub kind: FsKind,
+    /// Number of bytes the filesystem expects to be able to use, from the start of the partition
+    pub size_bytes: u64,
+}
+
+/// What a partition change would do to the filesystem inside it
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Impact {
+    /// No recognized filesystem
+    NoFilesystem,
+    /// The filesystem fits in the new partition size
+    Fits(FsInfo),
+    /// The filesystem is larger than the new partition size (`.1`, in bytes): it must be shrunk
+    /// first or data will be lost
+    Truncates(FsInfo, u64),
+    /// The partition is being deleted and contains this filesystem
+    Deletes(FsInfo),
+}
+
+impl Impact {
+    /// The change loses reachable filesystem data
+    pub fn is_destructive(&self) -> bool {
+        matches!(*self, Impact::Truncates(..) | Impact::Deletes(..))
+    }
+}
+
+fn le16(x: &[u8]) -> u64 {
+    x[0] as u64 | (x[1] as u64) << 8
+}
+
+fn le32(x: &[u8]) -> u64 {
+    le16(x) | le16(&x[2..]) << 16
+}
+
+fn le64(x: &[u8]) -> u64 {
+    le32(x) | le32(&x[4..]) << 32
+}
+
+fn be32(x: &[u8]) -> u64 {
+    (x[0] as u64) << 24 | (x[1] as u64) << 16 | (x[2] as u64) << 8 | x[3] as u64
+}
+
+fn be64(x: &[u8]) -> u64 {
+    be32(x) << 32 | be32(&x[4..])
+}
+
+/// Read `len` bytes at `offs` within the partition, or `None` if that lies outside the partition
+/// or the device.
+fn read_part<T: ReadAt + ?Sized>(dev: &T, part_offs: u64, part_len: u64, offs: u64, len: usize)
+    -> io_at::Result<Option<Vec<u8>>>
+{
+    if offs + len as u64 > part_len {
+        return Ok(None);
+    }
+    let mut b = vec![0u8; len];
+    match ::read_exact_at(dev, &mut b, part_offs + offs) {
+        Ok(()) => Ok(Some(b)),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn probe_ext(sb: &[u8]) -> Option<u64> {
+    if le16(&sb[0x38..]) != 0xEF53 {
+        return None;
+    }
+    let log = le32(&sb[0x18..]);
+    if log > 6 {
+        return None;
+    }
+    let mut blocks = le32(&sb[0x04..]);
+    /* INCOMPAT_64BIT */
+    if le32(&sb[0x60..]) & 0x80 != 0 {
+        blocks |= le32(&sb[0x150..]) << 32;
+    }
+    blocks.checked_mul(1024 << log)
+}
+
+fn probe_xfs(sb: &[u8]) -> Option<u64> {
+    if &sb[..4] != b"XFSB" {
+        return None;
+    }
+    be64(&sb[8..]).checked_mul(be32(&sb[4..]))
+}
+
+fn probe_btrfs(sb: &[u8]) -> Option<u64> {
+    if &sb[0x40..0x48] != b"_BHRfS_M" {
+        return None;
+    }
+    /* dev_item.total_bytes: the size of this device, rather than the sum over all devices in the
+     * filesystem (at 0x70) */
+    Some(le64(&sb[0xD1..]))
+}
+
+fn sector_size_ok(bps: u64) -> bool {
+    (512..=4096).contains(&bps) && bps.is_power_of_two()
+}
+
+fn probe_ntfs(bs: &[u8]) -> Option<u64> {
+    if &bs[3..11] != b"NTFS    " {
+        return None;
+    }
+    let bps = le16(&bs[0x0B..]);
+    if !sector_size_ok(bps) {
+        return None;
+    }
+    /* the backup boot sector is kept in the sector after the last one counted */
+    le64(&bs[0x28..]).checked_add(1).and_then(|n| n.checked_mul(bps))
+}
+
+fn probe_fat(bs: &[u8]) -> Option<u64> {
+    if bs[510] != 0x55 || bs[511] != 0xAA {
+        return None;
+    }
+    if &bs[54..57] != b"FAT" && &bs[82..87] != b"FAT32" {
+        return None;
+    }
+    let bps = le16(&bs[11..]);
+    if !sector_size_ok(bps) {
+        return None;
+    }
+    let total = match le16(&bs[19..]) {
+        0 => le32(&bs[32..]),
+        n => n,
+    };
+    Some(total * bps)
+}
+
+/// Look for a filesystem in the partition starting at byte `part_offs` of `dev` and spanning
+/// `part_len` bytes. No reads are made outside of the partition.
+pub fn probe<T: ReadAt + ?Sized>(dev: &T, part_offs: u64, part_len: u64)
+    -> io_at::Result<Option<FsInfo>>
+{
+    let found = |kind, size: Option<u64>| size.map(|size_bytes| FsInfo { kind, size_bytes });
+
+    if let Some(sb) = read_part(dev, part_offs, part_len, 1024, 1024)? {
+        if let Some(i) = found(FsKind::Ext, probe_ext(&sb)) {
+            return Ok(Some(i));
+        }
+    }
+    if let Some(bs) = read_part(dev, part_offs, part_len, 0, 512)? {
+        let i = found(FsKind::Xfs, probe_xfs(&bs))
+            .or_else(|| found(FsKind::Ntfs, probe_ntfs(&bs)))
+            .or_else(|| found(FsKind::Fat, probe_fat(&bs)));
+        if i.is_some() {
+            return Ok(i);
+        }
+    }
+    if let Some(sb) = read_part(dev, part_offs, part_len, 0x10000, 0x1000)? {
+        return Ok(found(FsKind::Btrfs, probe_btrfs(&sb)));
+    }
+    Ok(None)
+}
+
+/// Impact of changing the size of the partition at byte `part_offs` (currently `part_len` bytes)
+/// to `new_len` bytes.
+pub fn resize_impact<T: ReadAt + ?Sized>(dev: &T, part_offs: u64, part_len: u64, new_len: u64)
+    -> io_at::Result<Impact>
+{
+    Ok(match probe(dev, part_offs, part_len)? {
+        None => Impact::NoFilesystem,
+        Some(fs) if fs.size_bytes > new_len => Impact::Truncates(fs, new_len),
+        Some(fs) => Impact::Fits(fs),
+    })
+}
+
+/// Impact of deleting the partition at byte `part_offs` spanning `part_len` bytes
+pub fn delete_impact<T: ReadAt + ?Sized>(dev: &T, part_offs: u64, part_len: u64)
+    -> io_at::Result<Impact>
+{
+    Ok(match probe(dev, part_offs, part_len)? {
+        None => Impact::NoFilesystem,
+        Some(fs) => Impact::Deletes(fs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_disk::MemDisk;
+
+    const PART: usize = 1 << 20;
+    const LEN: u64 = 4 << 20;
+
+    fn disk() -> MemDisk {
+        MemDisk::new(512, 16384)
+    }
+
+    #[test]
+    fn ext4() {
+        let mut d = disk();
+        let sb = PART + 1024;
+        d.data[sb + 0x38..sb + 0x3A].copy_from_slice(&[0x53, 0xEF]);
+        d.data[sb + 0x04..sb + 0x08].copy_from_slice(&1024u32.to_le_bytes());
+        d.data[sb + 0x18..sb + 0x1C].copy_from_slice(&2u32.to_le_bytes());
+        let fs = probe(&d, PART as u64, LEN).unwrap().unwrap();
+        assert_eq!(fs, FsInfo { kind: FsKind::Ext, size_bytes: 4 << 20 });
+
+        assert_eq!(resize_impact(&d, PART as u64, LEN, 4 << 20).unwrap(), Impact::Fits(fs));
+        let i = resize_impact(&d, PART as u64, LEN, 2 << 20).unwrap();
+        assert_eq!(i, Impact::Truncates(fs, 2 << 20));
+        assert!(i.is_destructive());
+        assert_eq!(delete_impact(&d, PART as u64, LEN).unwrap(), Impact::Deletes(fs));
+    }
+
+    #[test]
+    fn xfs_ntfs_fat() {
+        let mut d = disk();
+        d.data[PART..PART + 4].copy_from_slice(b"XFSB");
+        d.data[PART + 4..PART + 8].copy_from_slice(&4096u32.to_be_bytes());
+        d.data[PART + 8..PART + 16].copy_from_slice(&2048u64.to_be_bytes());
+        assert_eq!(probe(&d, PART as u64, LEN).unwrap(),
+                   Some(FsInfo { kind: FsKind::Xfs, size_bytes: 8 << 20 }));
+
+        let mut d = disk();
+        d.data[PART + 3..PART + 11].copy_from_slice(b"NTFS    ");
+        d.data[PART + 0x0B..PART + 0x0D].copy_from_slice(&512u16.to_le_bytes());
+        d.data[PART + 0x28..PART + 0x30].copy_from_slice(&8191u64.to_le_bytes());
+        assert_eq!(probe(&d, PART as u64, LEN).unwrap(),
+                   Some(FsInfo { kind: FsKind::Ntfs, size_bytes: 4 << 20 }));
+
+        let mut d = disk();
+        d.data[PART + 82..PART + 87].copy_from_slice(b"FAT32");
+        d.data[PART + 11..PART + 13].copy_from_slice(&512u16.to_le_bytes());
+        d.data[PART + 32..PART + 36].copy_from_slice(&4096u32.to_le_bytes());
+        d.data[PART + 510] = 0x55;
+        d.data[PART + 511] = 0xAA;
+        assert_eq!(probe(&d, PART as u64, LEN).unwrap(),
+                   Some(FsInfo { kind: FsKind::Fat, size_bytes: 2 << 20 }));
+    }
+
+    #[test]
+    fn btrfs_and_bounds() {
+        let mut d = disk();
+        let sb = PART + 0x10000;
+        d.data[sb + 0x40..sb + 0x48].copy_from_slice(b"_BHRfS_M");
+        /* a 2 device filesystem */
+        d.data[sb + 0x70..sb + 0x78].copy_from_slice(&(9u64 << 20).to_le_bytes());
+        d.data[sb + 0xD1..sb + 0xD9].copy_from_slice(&(5u64 << 20).to_le_bytes());
+        assert_eq!(probe(&d, PART as u64, LEN).unwrap(),
+                   Some(FsInfo { kind: FsKind::Btrfs, size_bytes: 5 << 20 }));
+        /* the superblock is outside of a 64 KiB partition */
+        assert_eq!(probe(&d, PART as u64, 0x10000).unwrap(), None);
+        /* partition runs off the end of the device */
+        assert_eq!(probe(&d, (8 << 20) - 512, LEN).unwrap(), None);
+        assert_eq!(delete_impact(&disk(), PART as u64, LEN).unwrap(), Impact::NoFilesystem);
+    }
+}
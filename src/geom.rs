@@ -0,0 +1,199 @@
+//! Conversions between byte offsets, logical block addresses (LBAs) and cylinder-head-sector
+//! (CHS) addresses
+//!
+//! Every conversion that can land between two representable values takes an explicit
+//! `Rounding`, and every conversion that can overflow or fall outside of the destination's range
+//! returns `None` instead of wrapping or clamping (unless its name says it saturates).
+use mbr::header::Chs;
+
+/// How to handle a value that falls between two representable values
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Rounding {
+    /// Towards zero, ie: to the unit containing the value
+    Down,
+    /// Away from zero, ie: to the first unit boundary at or after the value
+    Up,
+    /// Fail (return `None`) unless the value is already on a boundary
+    Exact,
+}
+
+/// Round `v` to a multiple of `m` according to `r`
+///
+/// Returns `None` if `m` is 0, if rounding up overflows, or if `r` is `Exact` and `v` is not a
+/// multiple of `m`.
+pub fn round_to_multiple(v: u64, m: u64, r: Rounding) -> Option<u64> {
+    if m == 0 {
+        return None;
+    }
+    let rem = v % m;
+    if rem == 0 {
+        return Some(v);
+    }
+    match r {
+        Rounding::Down => Some(v - rem),
+        Rounding::Up => v.checked_add(m - rem),
+        Rounding::Exact => None,
+    }
+}
+
+/// Convert a byte offset into a block address with `sector_size` byte blocks
+pub fn bytes_to_lba(bytes: u64, sector_size: u64, r: Rounding) -> Option<u64> {
+    round_to_multiple(bytes, sector_size, r).map(|b| b / sector_size)
+}
+
+/// Convert a block address with `sector_size` byte blocks to a byte offset
+pub fn lba_to_bytes(lba: u64, sector_size: u64) -> Option<u64> {
+    lba.checked_mul(sector_size)
+}
+
+/// Convert a block address between 2 sector sizes (for example, between a 512 byte emulated and
+/// 4096 byte native sector size)
+pub fn lba_to_lba(lba: u64, from_size: u64, to_size: u64, r: Rounding) -> Option<u64> {
+    if to_size == 0 {
+        return None;
+    }
+    /* the intermediate byte offset may not fit in a u64 even if the result does */
+    let bytes = lba as u128 * from_size as u128;
+    let to = to_size as u128;
+    let q = match (bytes % to, r) {
+        (0, _) => bytes / to,
+        (_, Rounding::Down) => bytes / to,
+        (_, Rounding::Up) => bytes / to + 1,
+        (_, Rounding::Exact) => return None,
+    };
+    if q > u64::MAX as u128 {
+        None
+    } else {
+        Some(q as u64)
+    }
+}
+
+/// Largest cylinder number a MBR CHS address can hold
+pub const CHS_MAX_CYLINDER: u16 = 1023;
+
+/// A BIOS style disk geometry used to translate between LBA and CHS
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct Geometry {
+    heads: u16,
+    sectors: u8,
+}
+
+impl Geometry {
+    /// The geometry assumed by nearly all modern tools: 255 heads, 63 sectors per track
+    pub const LBA_ASSIST: Geometry = Geometry { heads: 255, sectors: 63 };
+
+    /// `heads` must be in `1..=256` and `sectors` (per track) in `1..=63`, otherwise `None` is
+    /// returned.
+    pub fn new(heads: u16, sectors: u8) -> Option<Self> {
+        if heads == 0 || heads > 256 || sectors == 0 || sectors > 63 {
+            None
+        } else {
+            Some(Geometry { heads, sectors })
+        }
+    }
+
+    pub fn heads(&self) -> u16 {
+        self.heads
+    }
+
+    pub fn sectors(&self) -> u8 {
+        self.sectors
+    }
+
+    /// Number of sectors in 1 cylinder
+    pub fn cylinder_size(&self) -> u64 {
+        self.heads as u64 * self.sectors as u64
+    }
+
+    /// Number of sectors addressable via CHS with this geometry
+    pub fn chs_capacity(&self) -> u64 {
+        (CHS_MAX_CYLINDER as u64 + 1) * self.cylinder_size()
+    }
+
+    /// Convert an LBA to a CHS address. `None` if it is past the last addressable cylinder.
+    pub fn lba_to_chs(&self, lba: u64) -> Option<Chs> {
+        if lba >= self.chs_capacity() {
+            return None;
+        }
+        let spt = self.sectors as u64;
+        let c = lba / self.cylinder_size();
+        let h = (lba / spt) % self.heads as u64;
+        let s = lba % spt + 1;
+        Chs::new(c as u16, h as u8, s as u8)
+    }
+
+    /// Like `lba_to_chs()`, but an LBA past the last addressable cylinder is converted to the
+    /// last addressable CHS address (as most partitioning tools do) instead of failing.
+    pub fn lba_to_chs_saturating(&self, lba: u64) -> Chs {
+        let last = self.chs_capacity() - 1;
+        self.lba_to_chs(std::cmp::min(lba, last)).unwrap()
+    }
+
+    /// Convert a CHS address to an LBA. `None` if the head or sector is outside this geometry or
+    /// the sector is 0 (sectors are numbered from 1).
+    pub fn chs_to_lba(&self, chs: &Chs) -> Option<u64> {
+        if chs.h() as u16 >= self.heads || chs.s() == 0 || chs.s() > self.sectors {
+            return None;
+        }
+        Some((chs.c() as u64 * self.heads as u64 + chs.h() as u64) * self.sectors as u64
+             + chs.s() as u64 - 1)
+    }
+
+    /// Round an LBA to a cylinder boundary
+    pub fn round_to_cylinder(&self, lba: u64, r: Rounding) -> Option<u64> {
+        round_to_multiple(lba, self.cylinder_size(), r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_lba() {
+        assert_eq!(bytes_to_lba(1 << 20, 512, Rounding::Exact), Some(2048));
+        assert_eq!(bytes_to_lba(1000, 512, Rounding::Exact), None);
+        assert_eq!(bytes_to_lba(1000, 512, Rounding::Down), Some(1));
+        assert_eq!(bytes_to_lba(1000, 512, Rounding::Up), Some(2));
+        assert_eq!(bytes_to_lba(1000, 0, Rounding::Down), None);
+        assert_eq!(lba_to_bytes(2048, 4096), Some(8 << 20));
+        assert_eq!(lba_to_bytes(u64::MAX, 2), None);
+        assert_eq!(round_to_multiple(u64::MAX, 4096, Rounding::Up), None);
+    }
+
+    #[test]
+    fn lba_between_sizes() {
+        assert_eq!(lba_to_lba(2048, 512, 4096, Rounding::Exact), Some(256));
+        assert_eq!(lba_to_lba(2049, 512, 4096, Rounding::Exact), None);
+        assert_eq!(lba_to_lba(2049, 512, 4096, Rounding::Down), Some(256));
+        assert_eq!(lba_to_lba(2049, 512, 4096, Rounding::Up), Some(257));
+        assert_eq!(lba_to_lba(256, 4096, 512, Rounding::Exact), Some(2048));
+        assert_eq!(lba_to_lba(u64::MAX, 4096, 4096, Rounding::Exact), Some(u64::MAX));
+        assert_eq!(lba_to_lba(u64::MAX, 4096, 512, Rounding::Exact), None);
+    }
+
+    #[test]
+    fn chs() {
+        let g = Geometry::LBA_ASSIST;
+        let c = g.lba_to_chs(0).unwrap();
+        assert_eq!((c.c(), c.h(), c.s()), (0, 0, 1));
+        let c = g.lba_to_chs(2048).unwrap();
+        assert_eq!((c.c(), c.h(), c.s()), (0, 32, 33));
+        assert_eq!(g.chs_to_lba(&c), Some(2048));
+
+        let last = g.chs_capacity() - 1;
+        let c = g.lba_to_chs(last).unwrap();
+        assert_eq!((c.c(), c.h(), c.s()), (1023, 254, 63));
+        assert_eq!(g.lba_to_chs(last + 1), None);
+        assert_eq!(g.lba_to_chs_saturating(u64::MAX), c);
+
+        for lba in (0..g.chs_capacity()).step_by(7919) {
+            assert_eq!(g.chs_to_lba(&g.lba_to_chs(lba).unwrap()), Some(lba));
+        }
+
+        assert_eq!(g.chs_to_lba(&Chs::new(0, 0, 0).unwrap()), None);
+        assert_eq!(Geometry::new(16, 63).unwrap().chs_to_lba(&Chs::new(0, 16, 1).unwrap()), None);
+        assert_eq!(g.round_to_cylinder(2048, Rounding::Up), Some(16065));
+        assert_eq!(Geometry::new(0, 63), None);
+    }
+}
@@ -5,3 +5,463 @@
 // LBA 0 contains a MBR
 //
 // "protective MBR" is a special case
+use io_at;
+use io_at::{ReadAt};
+use io_block::{BlockSize};
+use geom::Geometry;
+use mbr::header::MbrHeader;
+use limits;
+use limits::{Limit, LimitExceeded, Scheme};
+
+/// "EFI PART"
+pub const SIGNATURE: [u8;8] = *b"EFI PART";
+
+/// MBR partition type used by the protective MBR
+pub const PROTECTIVE_MBR_TYPE: u8 = 0xEE;
+
+const CRC_TABLE: [u32;256] = crc_table();
+
+const fn crc_table() -> [u32;256] {
+    let mut t = [0u32;256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+}
+
+/// The CRC32 used for the header and partition entry array checksums
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, &b| CRC_TABLE[((c ^ b as u32) & 0xFF) as usize] ^ (c >> 8))
+}
+
+fn r32(x: &[u8]) -> u32 {
+    x[0] as u32 | (x[1] as u32) << 8 | (x[2] as u32) << 16 | (x[3] as u32) << 24
+}
+
+fn r64(x: &[u8]) -> u64 {
+    r32(x) as u64 | (r32(&x[4..]) as u64) << 32
+}
+
+/// A GPT header, as found in the primary (LBA 1) and backup (last LBA) locations
+pub struct GptHeader<'a> {
+    data: &'a [u8;512]
+}
+
+impl<'a> GptHeader<'a> {
+    pub fn from_bytes(data: &'a [u8;512]) -> Self {
+        GptHeader { data }
+    }
+
+    pub fn signature(&self) -> [u8;8] {
+        *index_fixed!(&self.data; 0, .. 8)
+    }
+
+    pub fn revision(&self) -> u32 {
+        r32(&self.data[8..12])
+    }
+
+    pub fn header_size(&self) -> u32 {
+        r32(&self.data[12..16])
+    }
+
+    pub fn header_crc32(&self) -> u32 {
+        r32(&self.data[16..20])
+    }
+
+    /// LBA containing this header
+    pub fn my_lba(&self) -> u64 {
+        r64(&self.data[24..32])
+    }
+
+    /// LBA containing the other header
+    pub fn alternate_lba(&self) -> u64 {
+        r64(&self.data[32..40])
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        r64(&self.data[40..48])
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        r64(&self.data[48..56])
+    }
+
+    /// Disk GUID, in on-disk byte order
+    pub fn disk_guid(&self) -> [u8;16] {
+        *index_fixed!(&self.data; 56, .. 72)
+    }
+
+    pub fn partition_entry_lba(&self) -> u64 {
+        r64(&self.data[72..80])
+    }
+
+    pub fn num_partition_entries(&self) -> u32 {
+        r32(&self.data[80..84])
+    }
+
+    pub fn partition_entry_size(&self) -> u32 {
+        r32(&self.data[84..88])
+    }
+
+    pub fn partition_entry_array_crc32(&self) -> u32 {
+        r32(&self.data[88..92])
+    }
+
+    /// Checksum of the header as it should be stored in `header_crc32()`
+    pub fn compute_crc32(&self) -> Option<u32> {
+        let sz = self.header_size() as usize;
+        if !(92..=512).contains(&sz) {
+            return None;
+        }
+        let mut h = self.data[..sz].to_owned();
+        h[16..20].copy_from_slice(&[0;4]);
+        Some(crc32(&h))
+    }
+
+    /// The signature and header checksum are correct
+    ///
+    /// This does not examine the partition entry array.
+    pub fn is_valid(&self) -> bool {
+        self.signature() == SIGNATURE && self.compute_crc32() == Some(self.header_crc32())
+    }
+}
+
+/// Build a GUID in on-disk byte order from its textual fields (the first 3 fields are stored
+/// little endian, the rest as written).
+pub const fn guid(a: u32, b: u16, c: u16, d: [u8;8]) -> [u8;16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+     d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+pub const TYPE_EFI_SYSTEM: [u8;16] =
+    guid(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+pub const TYPE_BASIC_DATA: [u8;16] =
+    guid(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+pub const TYPE_LINUX_FILESYSTEM: [u8;16] =
+    guid(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+pub const TYPE_LINUX_SWAP: [u8;16] =
+    guid(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]);
+pub const TYPE_LINUX_LVM: [u8;16] =
+    guid(0xE6D6D379, 0xF507, 0x44C2, [0xA2, 0x3C, 0x23, 0x8F, 0x2A, 0x3D, 0xF9, 0x28]);
+pub const TYPE_LINUX_RAID: [u8;16] =
+    guid(0xA19D880F, 0x05FC, 0x4D3B, [0xA0, 0x06, 0x74, 0x3F, 0x0F, 0x84, 0x91, 0x1E]);
+
+/// The GPT partition type corresponding to a MBR partition type
+///
+/// Types without a GPT equivalent become "Linux filesystem", as gdisk does.
+pub fn type_for_mbr(part_type: u8) -> [u8;16] {
+    match part_type {
+        0x01 | 0x04 | 0x06 | 0x07 | 0x0B | 0x0C | 0x0E => TYPE_BASIC_DATA,
+        0xEF => TYPE_EFI_SYSTEM,
+        0x82 => TYPE_LINUX_SWAP,
+        0x8E => TYPE_LINUX_LVM,
+        0xFD => TYPE_LINUX_RAID,
+        _ => TYPE_LINUX_FILESYSTEM,
+    }
+}
+
+/// A random (version 4) GUID
+///
+/// The randomness comes from the per-process random keys of `std`'s `RandomState`, which is
+/// sufficient for uniqueness but is not suitable for anything security sensitive.
+pub fn random_guid() -> [u8;16] {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut g = [0u8;16];
+    for (i, c) in g.chunks_mut(8).enumerate() {
+        let mut h = RandomState::new().build_hasher();
+        h.write_usize(i);
+        c.copy_from_slice(&h.finish().to_le_bytes());
+    }
+    g[7] = (g[7] & 0x0F) | 0x40;
+    g[8] = (g[8] & 0x3F) | 0x80;
+    g
+}
+
+/// Attribute bit: legacy BIOS bootable (the GPT equivalent of the MBR active flag)
+pub const ATTR_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// Size of the entries this crate writes. Readers must handle other (larger) sizes.
+pub const ENTRY_SIZE: u32 = 128;
+
+/// A partition entry, ready to be serialized
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct GptEntry {
+    pub type_guid: [u8;16],
+    pub unique_guid: [u8;16],
+    pub first_lba: u64,
+    /// Inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    /// At most 36 UTF-16 code units, anything beyond that is dropped
+    pub name: String,
+}
+
+impl GptEntry {
+    pub fn to_bytes(&self) -> [u8;128] {
+        let mut e = [0u8;128];
+        e[0..16].copy_from_slice(&self.type_guid);
+        e[16..32].copy_from_slice(&self.unique_guid);
+        e[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        e[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        e[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, u) in self.name.encode_utf16().take(36).enumerate() {
+            e[56 + i * 2..58 + i * 2].copy_from_slice(&u.to_le_bytes());
+        }
+        e
+    }
+}
+
+/// Placement of the GPT structures on a device, with both partition entry arrays adjacent to
+/// their header (the primary array at LBA 2, the backup array just before the backup header).
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct GptLayout {
+    pub block_size: u64,
+    pub block_count: u64,
+    /// Number of entries in each partition entry array
+    pub entries: u32,
+}
+
+impl GptLayout {
+    /// UEFI 2.6, 5.3.2: at least 16384 bytes must be reserved for the partition entry array
+    pub const MIN_ARRAY_BYTES: u64 = 16384;
+
+    /// A layout with the smallest entry array the spec allows (128 entries of 128 bytes)
+    pub fn new(block_size: u64, block_count: u64) -> Self {
+        GptLayout {
+            block_size,
+            block_count,
+            entries: (Self::MIN_ARRAY_BYTES / ENTRY_SIZE as u64) as u32,
+        }
+    }
+
+    /// Bytes used by `entries` entries, which may be less than the space reserved for the array
+    fn entry_bytes(&self) -> u64 {
+        /* can't overflow: at most (2^32 - 1) * 2^7 */
+        self.entries as u64 * ENTRY_SIZE as u64
+    }
+
+    /// Blocks occupied by one partition entry array
+    pub fn array_blocks(&self) -> u64 {
+        std::cmp::max(self.entry_bytes(), Self::MIN_ARRAY_BYTES).div_ceil(self.block_size)
+    }
+
+    pub fn last_lba(&self) -> u64 {
+        self.block_count - 1
+    }
+
+    pub fn primary_array_lba(&self) -> u64 {
+        2
+    }
+
+    pub fn backup_array_lba(&self) -> u64 {
+        self.last_lba() - self.array_blocks()
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        self.primary_array_lba() + self.array_blocks()
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.backup_array_lba() - 1
+    }
+
+    /// The device is large enough to hold both copies of the GPT structures and at least 1
+    /// usable block
+    pub fn fits(&self) -> bool {
+        self.block_size >= 512 && self.block_count > 2 * (self.array_blocks() + 1) + 1
+    }
+
+    /// Check that `count` partitions fit in `entries`
+    pub fn check_count(&self, count: usize) -> Result<(), LimitExceeded> {
+        limits::check(Scheme::Gpt, Limit::Entries, self.entries, count as u64)
+    }
+
+    /// Serialize `parts` into a partition entry array, padded to `array_blocks()`
+    ///
+    /// Panics:
+    ///
+    ///  - if there are more parts than `entries` (see `check_count()`)
+    pub fn entry_array(&self, parts: &[GptEntry]) -> Vec<u8> {
+        assert!(parts.len() <= self.entries as usize,
+                "{} partitions don't fit in {} GPT entries", parts.len(), self.entries);
+        let mut a = vec![0u8; (self.array_blocks() * self.block_size) as usize];
+        for (i, p) in parts.iter().enumerate() {
+            a[i * 128..(i + 1) * 128].copy_from_slice(&p.to_bytes());
+        }
+        a
+    }
+
+    /// Serialize the primary (`primary == true`) or backup header block
+    ///
+    /// `array` is the output of `entry_array()`.
+    pub fn header(&self, primary: bool, disk_guid: &[u8;16], array: &[u8]) -> Vec<u8> {
+        let (my, alt, array_lba) = if primary {
+            (1, self.last_lba(), self.primary_array_lba())
+        } else {
+            (self.last_lba(), 1, self.backup_array_lba())
+        };
+        let array_crc = crc32(&array[..self.entry_bytes() as usize]);
+
+        let mut h = vec![0u8; self.block_size as usize];
+        h[0..8].copy_from_slice(&SIGNATURE);
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&my.to_le_bytes());
+        h[32..40].copy_from_slice(&alt.to_le_bytes());
+        h[40..48].copy_from_slice(&self.first_usable_lba().to_le_bytes());
+        h[48..56].copy_from_slice(&self.last_usable_lba().to_le_bytes());
+        h[56..72].copy_from_slice(disk_guid);
+        h[72..80].copy_from_slice(&array_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&self.entries.to_le_bytes());
+        h[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+        h[88..92].copy_from_slice(&array_crc.to_le_bytes());
+        let c = crc32(&h[..92]);
+        h[16..20].copy_from_slice(&c.to_le_bytes());
+        h
+    }
+}
+
+/// Read the first 512 bytes of block `lba`
+pub(crate) fn read_block_start<T: ReadAt + BlockSize>(dev: &T, lba: u64)
+    -> io_at::Result<[u8;512]>
+{
+    let mut b = [0u8;512];
+    ::read_exact_at(dev, &mut b, lba * dev.block_size_logical()?)?;
+    Ok(b)
+}
+
+/// Read the primary (LBA 1) and backup (last LBA) header locations, returning the raw blocks
+pub fn read_headers<T: ReadAt + BlockSize>(dev: &T) -> io_at::Result<([u8;512], [u8;512])> {
+    let last = dev.block_count()?.saturating_sub(1);
+    Ok((read_block_start(dev, 1)?, read_block_start(dev, last)?))
+}
+
+/// Build a protective MBR for a device of `block_count` blocks, keeping the bootcode and disk
+/// signature (bytes 0 to 445) of `existing`.
+///
+/// UEFI 2.6, 5.2.3: a single partition record of type 0xEE spanning from LBA 1 to the end of the
+/// disk (or as much of it as fits in 32 bits). The ending CHS is that of the last LBA, or
+/// 0xFFFFFF if it isn't representable.
+pub fn protective_mbr(existing: &[u8;512], block_count: u64) -> [u8;512] {
+    let mut m = [0u8;512];
+    m[..446].copy_from_slice(&existing[..446]);
+
+    let size = std::cmp::min(block_count.saturating_sub(1), 0xFFFF_FFFF);
+    let g = Geometry::LBA_ASSIST;
+    let chs_last = g.lba_to_chs(size).map_or([0xFF;3], |c| c.to_bytes());
+
+    let e = &mut m[446..462];
+    e[1..4].copy_from_slice(&g.lba_to_chs(1).unwrap().to_bytes());
+    e[4] = PROTECTIVE_MBR_TYPE;
+    e[5..8].copy_from_slice(&chs_last);
+    e[8..12].copy_from_slice(&1u32.to_le_bytes());
+    e[12..16].copy_from_slice(&(size as u32).to_le_bytes());
+
+    m[510] = 0x55;
+    m[511] = 0xAA;
+    m
+}
+
+impl<'a> MbrHeader<'a> {
+    /// Contains a partition record of type 0xEE starting at LBA 1 (UEFI 2.6, 5.2.3)
+    pub fn has_protective_entry(&self) -> bool {
+        self.primary_partitions().iter()
+            .any(|p| p.part_type() == PROTECTIVE_MBR_TYPE && p.lba_first() == 1)
+    }
+
+    /// A protective MBR: the only used partition record is the 0xEE one
+    pub fn is_protective(&self) -> bool {
+        self.bootsig_is_valid() && self.has_protective_entry() &&
+            self.primary_partitions().iter().filter(|p| p.is_used()).count() == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn guids() {
+        assert_eq!(TYPE_EFI_SYSTEM[..4], [0x28, 0x73, 0x2A, 0xC1]);
+        assert_eq!(TYPE_EFI_SYSTEM[8..], [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+        let (a, b) = (random_guid(), random_guid());
+        assert!(a != b);
+        assert_eq!(a[7] >> 4, 4);
+    }
+
+    #[test]
+    fn layout_headers() {
+        let l = GptLayout::new(512, 8192);
+        assert!(l.fits());
+        assert_eq!(l.array_blocks(), 32);
+        assert_eq!(l.first_usable_lba(), 34);
+        assert_eq!(l.last_usable_lba(), 8191 - 33);
+        assert_eq!(GptLayout::new(4096, 8192).first_usable_lba(), 6);
+        assert!(l.check_count(128).is_ok());
+        assert_eq!(l.check_count(129).unwrap_err().to_string(),
+                   "GPT allows at most 128 partition entries, but 129 were requested");
+
+        let a = l.entry_array(&[GptEntry {
+            type_guid: TYPE_LINUX_FILESYSTEM,
+            unique_guid: random_guid(),
+            first_lba: 2048,
+            last_lba: 4095,
+            attributes: 0,
+            name: "root".to_owned(),
+        }]);
+        let g = random_guid();
+        for &primary in [true, false].iter() {
+            let b = l.header(primary, &g, &a);
+            let h = GptHeader::from_bytes(index_fixed!(&b; 0, .. 512));
+            assert!(h.is_valid());
+            assert_eq!(h.partition_entry_array_crc32(), crc32(&a));
+            assert_eq!(h.disk_guid(), g);
+            assert_eq!(h.my_lba(), if primary { 1 } else { 8191 });
+        }
+        assert_eq!(&a[56..64], &[b'r', 0, b'o', 0, b'o', 0, b't', 0]);
+
+        /* sizes are computed without overflowing for any number of entries */
+        let mut l = GptLayout::new(512, 1 << 40);
+        l.entries = u32::MAX;
+        assert_eq!(l.array_blocks(), (u32::MAX as u64).div_ceil(4));
+        assert!(l.fits());
+    }
+
+    #[test]
+    fn protective() {
+        let old = [0x11u8;512];
+        let m = protective_mbr(&old, 1 << 20);
+        let h = MbrHeader::from_bytes(&m);
+        assert!(h.is_protective());
+        assert_eq!(&m[..446], &old[..446]);
+        let p = &h.primary_partitions()[0];
+        assert_eq!(p.lba_size(), (1 << 20) - 1);
+        assert_eq!(p.chs_first(), Geometry::LBA_ASSIST.lba_to_chs(1).unwrap());
+        assert!(!h.primary_partitions()[1].is_used());
+
+        /* too large for 32 bits or for CHS */
+        let m = protective_mbr(&old, 1 << 40);
+        let h = MbrHeader::from_bytes(&m);
+        assert_eq!(h.primary_partitions()[0].lba_size(), 0xFFFF_FFFF);
+        assert_eq!(h.primary_partitions()[0].chs_last().to_bytes(), [0xFF;3]);
+    }
+}
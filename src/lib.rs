@@ -21,6 +21,40 @@ extern crate index_fixed;
 
 pub mod mbr;
 pub mod gpt;
+pub mod cache;
+pub mod geom;
+pub mod conflict;
+pub mod remedy;
+pub mod convert;
+pub mod fs_probe;
+pub mod limits;
+
+#[cfg(test)]
+mod mem_disk;
+
+use io_at::ReadAt;
+
+/// Fill all of `buf` from `offs`, treating a short read as an error (`ReadAt` has no
+/// `read_exact` equivalent).
+pub(crate) fn read_exact_at<T: ReadAt + ?Sized>(store: &T, mut buf: &mut [u8], mut offs: u64)
+    -> io_at::Result<()>
+{
+    use std::io::{Error, ErrorKind};
+    while !buf.is_empty() {
+        match store.read_at(buf, offs) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof,
+                                           "failed to fill whole buffer")),
+            Ok(n) => {
+                let tmp = buf;
+                buf = &mut tmp[n..];
+                offs += n as u64;
+            },
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
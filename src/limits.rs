@@ -0,0 +1,86 @@
+//! Maximum partition counts of each partitioning scheme
+use std::fmt;
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Scheme::Mbr => "MBR",
+            Scheme::Gpt => "GPT",
+        })
+    }
+}
+
+/// The kind of partition being counted against a limit
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Limit {
+    /// MBR primary partitions (including the extended partition, if any)
+    Primary,
+    /// MBR logical partitions (in the EBR chain)
+    Logical,
+    /// GPT partition entries
+    Entries,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Limit::Primary => "primary partitions",
+            Limit::Logical => "logical partitions",
+            Limit::Entries => "partition entries",
+        })
+    }
+}
+
+/// Number of primary partition records in a MBR
+pub const MBR_MAX_PRIMARY: u32 = 4;
+
+/// Default limit on logical partitions: the 63 partitions per disk addressable with classic
+/// Linux device numbering, less the 4 primaries.
+pub const MBR_DEFAULT_MAX_LOGICAL: u32 = 59;
+
+/// More partitions were requested than `scheme` (as configured) can hold
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct LimitExceeded {
+    pub scheme: Scheme,
+    pub limit: Limit,
+    pub max: u32,
+    pub requested: u64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} allows at most {} {}, but {} were requested",
+               self.scheme, self.max, self.limit, self.requested)
+    }
+}
+
+impl ::std::error::Error for LimitExceeded {}
+
+/// Check `requested` against `max`
+pub fn check(scheme: Scheme, limit: Limit, max: u32, requested: u64)
+    -> Result<(), LimitExceeded>
+{
+    if requested > max as u64 {
+        Err(LimitExceeded { scheme, limit, max, requested })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message() {
+        let e = check(Scheme::Mbr, Limit::Primary, 4, 5).unwrap_err();
+        assert_eq!(e.to_string(), "MBR allows at most 4 primary partitions, but 5 were requested");
+        assert!(check(Scheme::Gpt, Limit::Entries, 128, 128).is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+//! Extended Boot Records (EBRs) and the logical partitions they describe
+//!
+//! An extended partition (a primary partition of type 0x05, 0x0F or 0x85) contains a linked list
+//! of EBRs. Each EBR is formatted like a MBR, but only uses its first 2 partition records:
+//!
+//!  - the first describes a logical partition, relative to the EBR's own LBA
+//!  - the second points to the next EBR, relative to the start of the extended partition (or is
+//!    empty for the last EBR)
+use std::io::{Error, ErrorKind};
+use io_at;
+use io_at::{ReadAt};
+use io_block::{BlockSize};
+use super::header::{MbrHeader, PartitionStatus};
+
+/// Upper bound on the length of a EBR chain that will be followed before it is considered to be
+/// corrupt (ie: contain a loop).
+pub const MAX_CHAIN_LEN: usize = 1 << 16;
+
+/// Partition types that mark an extended partition
+pub fn is_extended_type(part_type: u8) -> bool {
+    matches!(part_type, 0x05 | 0x0F | 0x85)
+}
+
+/// A logical partition with its location resolved to absolute LBAs
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct LogicalPartition {
+    pub part_type: u8,
+    pub bootable: bool,
+    /// Absolute LBA of the first block of the partition
+    pub lba_first: u64,
+    /// Size in blocks
+    pub lba_size: u64,
+    /// Absolute LBA of the EBR describing this partition
+    pub ebr_lba: u64,
+}
+
+fn corrupt(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Follow the EBR chain of the extended partition at `ext_first` (spanning `ext_size` blocks),
+/// returning the logical partitions in chain order.
+///
+/// An extended partition whose first EBR has no boot signature (or is all zeros) has no logical
+/// partitions yet, and gives an empty chain. A chain that leaves the extended partition, revisits
+/// an EBR, exceeds `MAX_CHAIN_LEN`, or links to an EBR without a boot signature results in an
+/// `InvalidData` error.
+pub fn read_chain<T: ReadAt + BlockSize>(dev: &T, ext_first: u64, ext_size: u64)
+    -> io_at::Result<Vec<LogicalPartition>>
+{
+    let bs = dev.block_size_logical()?;
+    let ext_end = ext_first + ext_size;
+    let mut parts = vec![];
+    let mut ebr_lba = ext_first;
+    let mut b = [0u8;512];
+
+    loop {
+        if parts.len() >= MAX_CHAIN_LEN {
+            return Err(corrupt("EBR chain too long"));
+        }
+        ::read_exact_at(dev, &mut b, ebr_lba * bs)?;
+        let h = MbrHeader::from_bytes(&b);
+        if !h.bootsig_is_valid() {
+            /* as Linux does, an unused first EBR means there are no logical partitions */
+            if ebr_lba == ext_first {
+                return Ok(parts);
+            }
+            return Err(corrupt("EBR has an invalid boot signature"));
+        }
+        let e = h.primary_partitions();
+
+        if e[0].is_used() {
+            let first = ebr_lba + e[0].lba_first() as u64;
+            let size = e[0].lba_size() as u64;
+            if first + size > ext_end {
+                return Err(corrupt("logical partition extends past its extended partition"));
+            }
+            parts.push(LogicalPartition {
+                part_type: e[0].part_type(),
+                bootable: matches!(e[0].status(), PartitionStatus::Active),
+                lba_first: first,
+                lba_size: size,
+                ebr_lba,
+            });
+        }
+
+        if !e[1].is_used() {
+            return Ok(parts);
+        }
+        let next = ext_first + e[1].lba_first() as u64;
+        /* the chain must move forward, which also rules out loops */
+        if next <= ebr_lba || next >= ext_end {
+            return Err(corrupt("EBR link points outside of the remaining extended partition"));
+        }
+        ebr_lba = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_disk::MemDisk;
+
+    #[test]
+    fn chain() {
+        let mut d = MemDisk::new(512, 8192);
+        d.set_mbr_part(0, 0x05, 2048, 6144);
+        d.set_part(2048, 0, 0x83, 2048, 1024);
+        d.set_part(2048, 1, 0x05, 2048, 4096);
+        d.set_part(4096, 0, 0x82, 64, 512);
+
+        let l = read_chain(&d, 2048, 6144).unwrap();
+        assert_eq!(l.len(), 2);
+        assert_eq!((l[0].part_type, l[0].lba_first, l[0].lba_size), (0x83, 4096, 1024));
+        assert_eq!((l[1].part_type, l[1].lba_first, l[1].ebr_lba), (0x82, 4160, 4096));
+    }
+
+    #[test]
+    fn empty_extended() {
+        let mut d = MemDisk::new(512, 8192);
+        d.set_mbr_part(0, 0x83, 64, 1024);
+        d.set_mbr_part(1, 0x05, 2048, 6144);
+        assert_eq!(read_chain(&d, 2048, 6144).unwrap(), vec![]);
+
+        let r = ::mbr::MbrReader::from_blockdev(&d);
+        assert_eq!(r.stats().unwrap().by_type.len(), 1);
+        assert!(::convert::ConvertPlan::new(&d, ::convert::ConvertMode::RequireZeroed).is_ok());
+
+        /* a later EBR without a signature is still an error */
+        d.set_part(2048, 0, 0x83, 64, 64);
+        d.set_part(2048, 1, 0x05, 1024, 64);
+        assert_eq!(read_chain(&d, 2048, 6144).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn loop_rejected() {
+        let mut d = MemDisk::new(512, 8192);
+        d.set_part(2048, 0, 0x83, 64, 64);
+        d.set_part(2048, 1, 0x05, 0, 4096);
+        assert_eq!(read_chain(&d, 2048, 6144).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
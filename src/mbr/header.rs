@@ -8,7 +8,7 @@ pub struct MbrHeader<'a> {
 
 impl<'a> MbrHeader<'a> {
     pub fn from_bytes(data: &'a [u8;512]) -> Self {
-        MbrHeader { data: data }
+        MbrHeader { data }
     }
 
     pub fn bootsig(&self) -> [u8;2] {
@@ -27,7 +27,7 @@ impl<'a> MbrHeader<'a> {
         self.data[444] as u16 | (self.data[445] as u16) << 8
     }
 
-    pub fn primary_partitions(&self) -> [PartitionEntry;4] {
+    pub fn primary_partitions(&self) -> [PartitionEntry<'_>;4] {
         [
             PartitionEntry { data: index_fixed!(&self.data; 446, ..462) },
             PartitionEntry { data: index_fixed!(&self.data; 462, ..478) },
@@ -74,6 +74,11 @@ impl<'a> PartitionEntry<'a> {
         self.data[4]
     }
 
+    /// An entry with type 0 or no blocks does not describe a partition
+    pub fn is_used(&self) -> bool {
+        self.part_type() != 0 && self.lba_size() != 0
+    }
+
     pub fn chs_first(&self) -> Chs {
         Chs { data: *index_fixed!(&self.data; 1,..4) }
     }
@@ -87,11 +92,27 @@ impl<'a> PartitionEntry<'a> {
 ///
 /// This is legacy stuff. Look at the LBA instead. If CHS can't represent the value, this will be
 /// 0xFFFFFF, (1023, 255, 63) on UEFI systems. Others may use (1023, 254, 63) for the same meaning.
+///
+/// See `geom::Geometry` for converting to and from LBAs.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
 pub struct Chs {
     data: [u8;3]
 }
 
 impl Chs {
+    /// `None` if `c` doesn't fit in 10 bits or `s` doesn't fit in 6 bits
+    pub fn new(c: u16, h: u8, s: u8) -> Option<Chs> {
+        if c > 1023 || s > 63 {
+            return None;
+        }
+        Some(Chs { data: [h, s | ((c >> 8) as u8) << 6, c as u8] })
+    }
+
+    /// The 3 bytes as stored in a partition entry
+    pub fn to_bytes(&self) -> [u8;3] {
+        self.data
+    }
+
     /// 10-bit cylinder
     pub fn c(&self) -> u16 {
         self.data[2] as u16 | (((self.data[1] >> 6) as u16) << 8)
@@ -8,10 +8,17 @@
 /// 
 //use std::convert::{From,Into};
 use io_block::{BlockSize};
+use io_at;
 use io_at::{ReadAt};
 
 pub mod writer;
 pub mod header;
+pub mod stats;
+pub mod ebr;
+
+use self::header::MbrHeader;
+use self::ebr::LogicalPartition;
+use self::stats::MbrStats;
 
 
 /*
@@ -29,4 +36,43 @@ impl<T: ReadAt + BlockSize> MbrReader<T> {
         MbrReader { store: back }
     }
 
+    /// The backing store this reader was created from
+    pub fn store(&self) -> &T {
+        &self.store
+    }
+
+    /// Read the 512 byte MBR from the start of the backing store
+    pub fn read_header(&self) -> io_at::Result<[u8;512]> {
+        let mut b = [0u8;512];
+        ::read_exact_at(&self.store, &mut b, 0)?;
+        Ok(b)
+    }
+
+    /// Logical partitions inside the (first) extended partition, in EBR chain order
+    ///
+    /// Empty if there is no extended partition. See `ebr::read_chain()`.
+    pub fn logical_partitions(&self) -> io_at::Result<Vec<LogicalPartition>> {
+        let b = self.read_header()?;
+        let h = MbrHeader::from_bytes(&b);
+        let ext = h.primary_partitions().iter()
+            .find(|p| p.is_used() && ebr::is_extended_type(p.part_type()))
+            .map(|p| (p.lba_first() as u64, p.lba_size() as u64));
+        match ext {
+            Some((first, size)) => ebr::read_chain(&self.store, first, size),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Capacity statistics for the primary and logical partitions
+    ///
+    /// Unlike `MbrHeader::stats()`, the extended partition is not counted as a partition itself:
+    /// each of the `logical_partitions()` is counted under its own type, the EBRs are counted as
+    /// allocated, and space inside the extended partition not used by either is free.
+    pub fn stats(&self) -> io_at::Result<MbrStats> {
+        let b = self.read_header()?;
+        let logical = self.logical_partitions()?;
+        let bs = self.store.block_size_logical()?;
+        let bc = self.store.block_count()?;
+        Ok(MbrHeader::from_bytes(&b).stats_with_logicals(bs, bc, stats::DEFAULT_ALIGN, &logical))
+    }
 }
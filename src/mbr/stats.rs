@@ -0,0 +1,232 @@
+//! Capacity accounting for a MBR partition table
+//!
+//! `MbrHeader::stats()` only examines the 4 primary entries, so an extended partition is
+//! accounted for as a single allocated region of its own type. `MbrReader::stats()` also reads
+//! the EBR chain, and accounts for each logical partition instead.
+use super::header::MbrHeader;
+use super::ebr::{is_extended_type, LogicalPartition};
+use geom::{round_to_multiple, Rounding};
+
+/// Alignment used by `MbrHeader::stats()` and `MbrReader::stats()`: 1 MiB, which is what most
+/// modern partitioning tools start partitions at.
+pub const DEFAULT_ALIGN: u64 = 1 << 20;
+
+/// Space used by all partitions of a single partition type
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct TypeUsage {
+    pub part_type: u8,
+    pub partitions: u32,
+    pub bytes: u64,
+}
+
+/// Partition-level capacity of a disk, in bytes
+///
+/// `allocated_bytes + free_bytes == total_bytes`. Overlapping partitions are only counted once in
+/// `allocated_bytes`, but are each counted in full in `by_type`.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct MbrStats {
+    /// Size of the entire device
+    pub total_bytes: u64,
+    /// Bytes covered by at least one partition, plus the MBR itself
+    pub allocated_bytes: u64,
+    /// Bytes not covered by any partition
+    pub free_bytes: u64,
+    /// Size of the largest contiguous region not covered by any partition
+    pub largest_free_extent: u64,
+    /// Usage for each partition type present, ordered by type
+    pub by_type: Vec<TypeUsage>,
+    /// Free bytes that can't be used by a new partition because they fall outside the alignment
+    /// boundaries of their free extent
+    pub alignment_slack: u64,
+}
+
+impl<'a> MbrHeader<'a> {
+    /// Compute capacity statistics for a device with the given geometry, using `DEFAULT_ALIGN`
+    ///
+    /// Only primary partitions are included: an extended partition counts as one partition of
+    /// its own type covering its whole span. Use `MbrReader::stats()` to break it out into its
+    /// logical partitions.
+    pub fn stats(&self, block_size: u64, block_count: u64) -> MbrStats {
+        self.stats_aligned(block_size, block_count, DEFAULT_ALIGN)
+    }
+
+    /// Compute capacity statistics, treating new partitions as needing to start and end on
+    /// multiples of `align` bytes when determining `alignment_slack`.
+    ///
+    /// Panics:
+    ///
+    ///  - if `align` is 0
+    pub fn stats_aligned(&self, block_size: u64, block_count: u64, align: u64) -> MbrStats {
+        let parts: Vec<_> = self.primary_partitions().iter().filter(|p| p.is_used())
+            .map(|p| (p.part_type(), p.lba_first() as u64, p.lba_size() as u64))
+            .collect();
+        compute(block_size, block_count, align, &parts, &[])
+    }
+
+    /// Like `stats_aligned()`, but with the extended partition replaced by `logical` (as read
+    /// from its EBR chain). The EBRs are counted as allocated, like the MBR.
+    pub(crate) fn stats_with_logicals(&self, block_size: u64, block_count: u64, align: u64,
+                                      logical: &[LogicalPartition]) -> MbrStats
+    {
+        let parts: Vec<_> = self.primary_partitions().iter()
+            .filter(|p| p.is_used() && !is_extended_type(p.part_type()))
+            .map(|p| (p.part_type(), p.lba_first() as u64, p.lba_size() as u64))
+            .chain(logical.iter().map(|l| (l.part_type, l.lba_first, l.lba_size)))
+            .collect();
+        let ebrs: Vec<_> = logical.iter().map(|l| l.ebr_lba).collect();
+        compute(block_size, block_count, align, &parts, &ebrs)
+    }
+}
+
+/// `parts` are `(type, first lba, size in blocks)`, `reserved` are single blocks of partition
+/// table metadata besides the MBR.
+fn compute(block_size: u64, block_count: u64, align: u64, parts: &[(u8, u64, u64)],
+           reserved: &[u64]) -> MbrStats
+{
+    assert!(align != 0, "alignment must be non-zero");
+
+    let total = block_size * block_count;
+    let mut by_type: Vec<TypeUsage> = vec![];
+
+    /* LBA 0 holds the MBR, so it is never free */
+    let mut extents = vec![(0, std::cmp::min(block_count, 1))];
+    extents.extend(reserved.iter().filter(|&&lba| lba < block_count).map(|&lba| (lba, lba + 1)));
+    for &(part_type, first, size) in parts.iter() {
+        let last = std::cmp::min(first + size, block_count);
+        let blocks = last.saturating_sub(first);
+
+        match by_type.binary_search_by_key(&part_type, |u| u.part_type) {
+            Ok(i) => {
+                by_type[i].partitions += 1;
+                by_type[i].bytes += blocks * block_size;
+            },
+            Err(i) => by_type.insert(i, TypeUsage {
+                part_type,
+                partitions: 1,
+                bytes: blocks * block_size,
+            }),
+        }
+
+        if blocks > 0 {
+            extents.push((first, last));
+        }
+    }
+    extents.sort();
+
+    let mut allocated = 0;
+    let mut largest_free = 0;
+    let mut slack = 0;
+    let mut free_start = 0;
+    let mut account_free = |start: u64, end: u64| {
+        if end <= start {
+            return;
+        }
+        let (s, e) = (start * block_size, end * block_size);
+        largest_free = std::cmp::max(largest_free, e - s);
+        let us = round_to_multiple(s, align, Rounding::Up).unwrap_or(e);
+        let ue = round_to_multiple(e, align, Rounding::Down).unwrap();
+        slack += if ue > us { (us - s) + (e - ue) } else { e - s };
+    };
+
+    for &(first, last) in extents.iter() {
+        if first > free_start {
+            account_free(free_start, first);
+        }
+        if last > free_start {
+            allocated += (last - std::cmp::max(first, free_start)) * block_size;
+            free_start = last;
+        }
+    }
+    account_free(free_start, block_count);
+
+    MbrStats {
+        total_bytes: total,
+        allocated_bytes: allocated,
+        free_bytes: total - allocated,
+        largest_free_extent: largest_free,
+        by_type,
+        alignment_slack: slack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_disk::MemDisk;
+
+    fn header_of(d: &MemDisk) -> [u8;512] {
+        *index_fixed!(&d.data; 0, .. 512)
+    }
+
+    #[test]
+    fn empty_table() {
+        let d = MemDisk::new(512, 4096);
+        let b = header_of(&d);
+        let s = MbrHeader::from_bytes(&b).stats(512, 4096);
+        assert_eq!(s.total_bytes, 512 * 4096);
+        assert_eq!(s.allocated_bytes, 512);
+        assert_eq!(s.free_bytes, 512 * 4095);
+        assert_eq!(s.largest_free_extent, 512 * 4095);
+        assert!(s.by_type.is_empty());
+        /* 1 MiB is used up by the MBR's alignment, the remaining 1 MiB is usable */
+        assert_eq!(s.alignment_slack, (1 << 20) - 512);
+    }
+
+    #[test]
+    fn partitions_and_gaps() {
+        /* 8 MiB disk */
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x83, 2048, 4096);
+        d.set_mbr_part(1, 0x83, 8192, 2048);
+        /* overlaps the second partition */
+        d.set_mbr_part(2, 0x07, 9216, 2048);
+        let b = header_of(&d);
+        let s = MbrHeader::from_bytes(&b).stats(512, 16384);
+
+        assert_eq!(s.allocated_bytes, 512 * (1 + 4096 + 2048 + 1024));
+        assert_eq!(s.free_bytes, s.total_bytes - s.allocated_bytes);
+        assert_eq!(s.largest_free_extent, 512 * (16384 - 11264));
+        assert_eq!(s.by_type, vec![
+            TypeUsage { part_type: 0x07, partitions: 1, bytes: 512 * 2048 },
+            TypeUsage { part_type: 0x83, partitions: 2, bytes: 512 * 6144 },
+        ]);
+        /* [1, 2048): all slack; [6144, 8192): aligned; [11264, 16384): aligned tail only */
+        assert_eq!(s.alignment_slack, 512 * 2047 + 512 * (12288 - 11264));
+    }
+
+    #[test]
+    fn partition_past_end() {
+        let mut d = MemDisk::new(512, 4096);
+        d.set_mbr_part(0, 0x0c, 2048, 8192);
+        let b = header_of(&d);
+        let s = MbrHeader::from_bytes(&b).stats(512, 4096);
+        assert_eq!(s.allocated_bytes, 512 * (1 + 2048));
+        assert_eq!(s.by_type[0].bytes, 512 * 2048);
+    }
+
+    #[test]
+    fn logicals() {
+        use mbr::MbrReader;
+
+        let mut d = MemDisk::new(512, 16384);
+        d.set_mbr_part(0, 0x83, 2048, 2048);
+        d.set_mbr_part(1, 0x05, 4096, 8192);
+        d.set_part(4096, 0, 0x83, 2048, 2048);
+        d.set_part(4096, 1, 0x05, 4096, 4096);
+        d.set_part(8192, 0, 0x82, 2048, 1024);
+
+        let b = header_of(&d);
+        let prim = MbrHeader::from_bytes(&b).stats(512, 16384);
+        assert_eq!(prim.by_type[0],
+                   TypeUsage { part_type: 0x05, partitions: 1, bytes: 512 * 8192 });
+
+        let s = MbrReader::from_blockdev(&d).stats().unwrap();
+        assert_eq!(s.by_type, vec![
+            TypeUsage { part_type: 0x82, partitions: 1, bytes: 512 * 1024 },
+            TypeUsage { part_type: 0x83, partitions: 2, bytes: 512 * 4096 },
+        ]);
+        /* MBR, 2 EBRs, 3 partitions */
+        assert_eq!(s.allocated_bytes, 512 * (1 + 2 + 2048 + 2048 + 1024));
+        assert_eq!(s.free_bytes, s.total_bytes - s.allocated_bytes);
+    }
+}
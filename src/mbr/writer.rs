@@ -2,6 +2,8 @@ use std::{time};
 use io_block::{BlockSize};
 use io_at;
 use io_at::{WriteAt};
+use limits;
+use limits::{Limit, LimitExceeded, Scheme};
 
 /// Identify another partition by it's relative or absolute index
 #[derive(Clone,PartialEq,Eq,Debug)]
@@ -64,6 +66,20 @@ pub struct MbrPartSpec {
 }
 
 impl MbrPartSpec {
+    pub fn new(specs: Vec<PartSpec>) -> Self {
+        MbrPartSpec { specs }
+    }
+
+    /// The partition number requested with `NumSpec::Exact`, if any
+    pub fn exact_number(&self) -> Option<u32> {
+        for s in self.specs.iter() {
+            if let PartSpec::Number(NumSpec::Exact(n)) = *s {
+                return Some(n);
+            }
+        }
+        None
+    }
+
     pub fn is_bootable(&self) -> bool {
         for s in self.specs.iter() {
             if let &PartSpec::IsBootable = s {
@@ -75,6 +91,7 @@ impl MbrPartSpec {
 }
 
 /// A physical (real) MBR partition with all associated attributes
+#[allow(dead_code)]
 #[derive(Clone)]
 pub struct MbrPhysPart {
     number: u32,
@@ -93,7 +110,7 @@ impl MbrPhysPart {
     }
 }
 
-#[derive(Clone,PartialEq,Eq)]
+#[derive(Clone,PartialEq,Eq,Debug)]
 pub enum MbrBuilderError {
     BootcodeOversized(usize),
     Bootcode2Oversized(usize),
@@ -101,6 +118,14 @@ pub enum MbrBuilderError {
     DiskSigOverlapped,
     BootCodeOverlapped(usize, usize),
     MoreThan1Bootable,
+    /// The partition specs need more primary or logical partitions than are available
+    TooManyPartitions(LimitExceeded),
+}
+
+impl From<LimitExceeded> for MbrBuilderError {
+    fn from(e: LimitExceeded) -> Self {
+        MbrBuilderError::TooManyPartitions(e)
+    }
 }
 
 /// Allows creating and commiting a new MBR to a WriteAt-able BlockSize-able thing (typically, a
@@ -113,6 +138,7 @@ pub struct MbrBuilder {
     timestamp: Option<time::SystemTime>,
     original_physical_drive: Option<u8>,
     disk_sig: Option<(u32,u16)>,
+    max_logical: u32,
 }
 
 impl MbrBuilder {
@@ -124,7 +150,8 @@ impl MbrBuilder {
             partitions: vec![],
             timestamp: None,
             original_physical_drive: None,
-            disk_sig: None
+            disk_sig: None,
+            max_logical: limits::MBR_DEFAULT_MAX_LOGICAL,
         }
     }
 
@@ -195,6 +222,13 @@ impl MbrBuilder {
         self
     }
 
+    /// Limit the number of logical partitions (partitions numbered 4 and up, stored in the
+    /// extended partition) that may be requested. Defaults to `limits::MBR_DEFAULT_MAX_LOGICAL`.
+    pub fn set_max_logical(mut self, max: u32) -> Self {
+        self.max_logical = max;
+        self
+    }
+
     fn is_modern(&self) -> bool {
         self.bootcode_2.is_some() ||
             self.original_physical_drive.is_some() ||
@@ -202,7 +236,44 @@ impl MbrBuilder {
             self.disk_sig.is_some()
     }
 
+    /// Check that the partitions fit in the 4 primary records and the configured number of
+    /// logical partitions.
+    ///
+    /// Partitions numbered 0 to 3 are primary, 4 and up are logical. Unnumbered partitions take
+    /// any free primary record before becoming logical. Once any logical partition is needed one
+    /// primary record is used by the extended partition.
+    fn count_check(&self) -> Result<(),LimitExceeded> {
+        let mut primary = 0u64;
+        let mut logical = 0u64;
+        let mut unnumbered = 0u64;
+        for p in self.partitions.iter() {
+            match p.exact_number() {
+                Some(n) if n < limits::MBR_MAX_PRIMARY => primary += 1,
+                Some(n) => {
+                    /* a logical partition number implies all the ones before it exist */
+                    let needed = (n - limits::MBR_MAX_PRIMARY) as u64 + 1;
+                    limits::check(Scheme::Mbr, Limit::Logical, self.max_logical, needed)?;
+                    logical += 1;
+                },
+                None => unnumbered += 1,
+            }
+        }
+
+        let max_primary = limits::MBR_MAX_PRIMARY as u64;
+        if logical == 0 && primary + unnumbered <= max_primary {
+            return Ok(());
+        }
+
+        /* an extended partition is required and occupies a primary record */
+        let max_primary = max_primary - 1;
+        limits::check(Scheme::Mbr, Limit::Primary, max_primary as u32, primary)?;
+        logical += unnumbered.saturating_sub(max_primary - primary);
+        limits::check(Scheme::Mbr, Limit::Logical, self.max_logical, logical)
+    }
+
     fn partition_check(&self) -> Result<(),MbrBuilderError> {
+        self.count_check()?;
+
         let mut fb = false;
         for p in self.partitions.iter() {
             /* only 1 bootable partition is allowed */
@@ -239,12 +310,18 @@ impl MbrBuilder {
             return Err(MbrBuilderError::BootCodeOverlapped(b1, b2));
         }
 
-        /* TODO: confirm that partition specification is valid */
+        self.partition_check()?;
 
         Ok(MbrWriter { inner: self })
     }
 }
 
+impl Default for MbrBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A MBR specification that may be directly commited to a device.
 pub struct MbrWriter {
     inner: MbrBuilder,
@@ -262,13 +339,53 @@ impl MbrWriter {
     /// _this_ function. Preservation is handled elsewhere by pre-configuring the builder.
     ///
     /// It is recommended that you ensure no unintended changes are made between read & commit.
-    pub fn commit<T: WriteAt + BlockSize>(&self, back: T) -> io_at::Result<()> {
+    pub fn commit<T: WriteAt + BlockSize>(&self, _back: T) -> io_at::Result<()> {
         /* 1. Confirm that given the size of the device, the requested partition specs result in an
          *    allowed layout (ie: they need to fit)
          */
 
 
         unimplemented!();
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(n: Option<u32>) -> MbrPartSpec {
+        MbrPartSpec::new(n.map(|n| PartSpec::Number(NumSpec::Exact(n))).into_iter().collect())
+    }
+
+    fn check(b: MbrBuilder) -> Option<MbrBuilderError> {
+        b.compile().err()
+    }
+
+    fn limit(limit: Limit, max: u32, requested: u64) -> Option<MbrBuilderError> {
+        Some(MbrBuilderError::TooManyPartitions(LimitExceeded {
+            scheme: Scheme::Mbr, limit, max, requested
+        }))
+    }
+
+    #[test]
+    fn primary_limit() {
+        let b = (0..4).fold(MbrBuilder::new(), |b, _| b.partition_add(spec(None)));
+        assert_eq!(check(b.clone()), None);
+        /* a 5th partition needs an extended partition, leaving room for 3 primaries */
+        assert_eq!(check(b.clone().partition_add(spec(None))), None);
+        let b = (0..4).fold(MbrBuilder::new(), |b, i| b.partition_add(spec(Some(i))));
+        assert_eq!(check(b.partition_add(spec(Some(4)))), limit(Limit::Primary, 3, 4));
+    }
+
+    #[test]
+    fn logical_limit() {
+        let b = MbrBuilder::new().set_max_logical(2);
+        assert_eq!(check(b.clone().partition_add(spec(Some(5)))), None);
+        assert_eq!(check(b.clone().partition_add(spec(Some(6)))), limit(Limit::Logical, 2, 3));
+
+        /* 3 primaries + 2 logicals */
+        let b5 = (0..5).fold(b, |b, _| b.partition_add(spec(None)));
+        assert_eq!(check(b5.clone()), None);
+        assert_eq!(check(b5.partition_add(spec(None))), limit(Limit::Logical, 2, 3));
     }
 }
@@ -0,0 +1,75 @@
+//! An in-memory block device for tests
+
+use io_at::{self, ReadAt, WriteAt};
+use io_block::{self, BlockSize};
+
+pub struct MemDisk {
+    pub data: Vec<u8>,
+    block_size: u64,
+}
+
+impl MemDisk {
+    pub fn new(block_size: u64, block_count: u64) -> Self {
+        MemDisk {
+            data: vec![0; (block_size * block_count) as usize],
+            block_size,
+        }
+    }
+
+    /// Fill the 16 byte partition entry in slot `idx` of the MBR or EBR at `sector`, and set
+    /// that sector's boot signature
+    pub fn set_part(&mut self, sector: u64, idx: usize, part_type: u8, lba_first: u32,
+                    lba_size: u32) {
+        let s = (sector * self.block_size) as usize;
+        let o = s + 446 + idx * 16;
+        let e = &mut self.data[o..o + 16];
+        e[4] = part_type;
+        e[8..12].copy_from_slice(&lba_first.to_le_bytes());
+        e[12..16].copy_from_slice(&lba_size.to_le_bytes());
+        self.data[s + 510] = 0x55;
+        self.data[s + 511] = 0xAA;
+    }
+
+    /// `set_part()` on the MBR
+    pub fn set_mbr_part(&mut self, idx: usize, part_type: u8, lba_first: u32, lba_size: u32) {
+        self.set_part(0, idx, part_type, lba_first, lba_size)
+    }
+}
+
+impl ReadAt for MemDisk {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io_at::Result<usize> {
+        self.data.read_at(buf, offs)
+    }
+}
+
+impl WriteAt for MemDisk {
+    fn write_at(&mut self, buf: &[u8], offs: u64) -> io_at::Result<usize> {
+        /* don't let writes grow the device */
+        let len = self.data.len() as u64;
+        if offs >= len {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len() as u64, len - offs) as usize;
+        self.data.write_at(&buf[..n], offs)
+    }
+}
+
+impl BlockSize for MemDisk {
+    fn block_size_logical(&self) -> io_block::Result<u64> {
+        Ok(self.block_size)
+    }
+
+    fn block_count(&self) -> io_block::Result<u64> {
+        Ok(self.data.len() as u64 / self.block_size)
+    }
+}
+
+impl BlockSize for &MemDisk {
+    fn block_size_logical(&self) -> io_block::Result<u64> {
+        (**self).block_size_logical()
+    }
+
+    fn block_count(&self) -> io_block::Result<u64> {
+        (**self).block_count()
+    }
+}
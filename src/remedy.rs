@@ -0,0 +1,82 @@
+//! Repairs for problems found by the checks in this crate
+//!
+//! Checks (such as `conflict::check()`) suggest `Remedy`s rather than modifying the device
+//! themselves. Applying one is left to the caller, as every remedy discards on-disk state.
+use std::io::{Error, ErrorKind};
+use io_at;
+use io_at::{ReadAt, WriteAt};
+use io_block::{BlockSize};
+use gpt;
+use gpt::GptHeader;
+use mbr::header::MbrHeader;
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Remedy {
+    /// Erase the primary and backup GPT headers, leaving the MBR as the only partition table.
+    ///
+    /// Only the header blocks are cleared (which is enough for the GPT to no longer be
+    /// recognized), the partition entry arrays are left in place.
+    ZapStaleGpt,
+
+    /// Replace the MBR's partition records with a single protective 0xEE record so that the
+    /// GPT is the only partition table. Bootcode and the disk signature are kept.
+    RebuildProtectiveMbr,
+}
+
+impl Remedy {
+    /// A short human readable explanation of what applying this remedy does
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            Remedy::ZapStaleGpt =>
+                "erase the stale GPT headers and keep the MBR partitions",
+            Remedy::RebuildProtectiveMbr =>
+                "replace the MBR partitions with a protective MBR and keep the GPT partitions",
+        }
+    }
+
+    /// Apply the remedy to `dev`
+    ///
+    /// Fails without writing if doing so would destroy data it is not meant to: `ZapStaleGpt`
+    /// refuses when a header block lies inside a MBR partition (it would then be partition data,
+    /// not a stale header), and `RebuildProtectiveMbr` refuses when there is no valid GPT header
+    /// to fall back on.
+    pub fn apply<T: ReadAt + WriteAt + BlockSize>(&self, dev: &mut T) -> io_at::Result<()> {
+        let bs = dev.block_size_logical()?;
+        let bc = dev.block_count()?;
+        let mut m = [0u8;512];
+        ::read_exact_at(&*dev, &mut m, 0)?;
+        let (primary, backup) = gpt::read_headers(&*dev)?;
+
+        match *self {
+            Remedy::ZapStaleGpt => {
+                let last = bc.saturating_sub(1);
+                let covered = |lba: u64| MbrHeader::from_bytes(&m).primary_partitions().iter()
+                    .filter(|p| p.is_used())
+                    .any(|p| {
+                        let first = p.lba_first() as u64;
+                        first <= lba && lba < first + p.lba_size() as u64
+                    });
+                if covered(1) || covered(last) {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          "GPT header location is inside a MBR partition"));
+                }
+
+                let zero = vec![0u8; bs as usize];
+                for &(lba, h) in [(1, &primary), (last, &backup)].iter() {
+                    if GptHeader::from_bytes(h).signature() == gpt::SIGNATURE {
+                        dev.write_all_at(&zero, lba * bs)?;
+                    }
+                }
+                Ok(())
+            },
+            Remedy::RebuildProtectiveMbr => {
+                if !GptHeader::from_bytes(&primary).is_valid() &&
+                    !GptHeader::from_bytes(&backup).is_valid() {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          "no valid GPT header present"));
+                }
+                dev.write_all_at(&gpt::protective_mbr(&m, bc), 0)
+            },
+        }
+    }
+}